@@ -0,0 +1,291 @@
+use crate::storage::{Filesystem, MountStack, Partition, PartitionType};
+use anyhow::{anyhow, Context};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use tempfile::tempdir;
+
+/// With A/B slots /boot lives in each slot, so the EFI system partition is mounted here instead
+pub const ESP_MOUNT_POINT: &str = "/efi";
+/// Names the slot an installation lives in, relative to its root
+pub const SLOT_FILE: &str = "etc/alma-slot";
+/// The GRUB environment block holding the slot state, relative to the EFI system partition
+pub const GRUBENV_PATH: &str = "grub/grubenv";
+
+pub const SERVICE_NAME: &str = "alma-boot-ok.service";
+pub const SCRIPT_PATH: &str = "usr/local/bin/alma-boot-ok";
+
+pub static SERVICE: &str = "[Unit]
+Description=Confirm that the running A/B slot boots
+After=multi-user.target
+
+[Service]
+Type=oneshot
+ExecStart=/usr/local/bin/alma-boot-ok
+
+[Install]
+WantedBy=multi-user.target
+";
+
+pub static SCRIPT: &str = r#"#!/bin/bash
+set -euo pipefail
+
+slot=$(cat /etc/alma-slot)
+grub-editenv /efi/grub/grubenv set "ALMA_${slot}_OK=1" "ALMA_${slot}_TRY=0"
+"#;
+
+/// The GRUB configuration on the EFI system partition, which picks a slot and loads the
+/// configuration generated inside it
+/// Booting a slot sets its TRY flag, which the alma-boot-ok service clears once the system is up.
+/// A slot which still has the flag set on the next boot is skipped, so GRUB falls back to the other
+/// slot.
+pub static GRUB_CONFIG: &str = r#"set timeout=3
+set ALMA_ORDER="A B"
+set ALMA_A_OK=1
+set ALMA_A_TRY=0
+set ALMA_B_OK=0
+set ALMA_B_TRY=0
+load_env ALMA_ORDER ALMA_A_OK ALMA_A_TRY ALMA_B_OK ALMA_B_TRY
+
+set default=""
+for slot in ${ALMA_ORDER}; do
+    if [ "${slot}" = A ]; then ok="${ALMA_A_OK}"; try="${ALMA_A_TRY}"; fi
+    if [ "${slot}" = B ]; then ok="${ALMA_B_OK}"; try="${ALMA_B_TRY}"; fi
+    if [ "${ok}" = 1 -a "${try}" = 0 ]; then
+        set default="alma-${slot}"
+        break
+    fi
+done
+
+# No slot is known to boot, so try the preferred one again
+if [ -z "${default}" ]; then
+    for slot in ${ALMA_ORDER}; do
+        set default="alma-${slot}"
+        break
+    done
+fi
+
+menuentry "Slot A" --id alma-A {
+    set ALMA_A_TRY=1
+    save_env ALMA_A_TRY
+    search --no-floppy --label --set=root alma_a
+    configfile /boot/grub/grub.cfg
+}
+
+menuentry "Slot B" --id alma-B {
+    set ALMA_B_TRY=1
+    save_env ALMA_B_TRY
+    search --no-floppy --label --set=root alma_b
+    configfile /boot/grub/grub.cfg
+}
+"#;
+
+/// GRUB only rewrites the environment block in place, so it always has exactly this size
+const GRUBENV_SIZE: usize = 1024;
+const GRUBENV_HEADER: &str = "# GRUB Environment Block\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    pub fn name(self) -> &'static str {
+        match self {
+            Slot::A => "A",
+            Slot::B => "B",
+        }
+    }
+
+    /// The filesystem label GRUB finds the slot by
+    pub fn label(self) -> &'static str {
+        match self {
+            Slot::A => "alma_a",
+            Slot::B => "alma_b",
+        }
+    }
+
+    pub fn index(self) -> usize {
+        match self {
+            Slot::A => 0,
+            Slot::B => 1,
+        }
+    }
+
+    pub fn other(self) -> Self {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "A" => Some(Slot::A),
+            "B" => Some(Slot::B),
+            _ => None,
+        }
+    }
+}
+
+/// The variables of a GRUB environment block
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct GrubEnv {
+    variables: Vec<(String, String)>,
+}
+
+impl GrubEnv {
+    /// The state of a new installation, where only slot A holds a system
+    pub fn initial() -> Self {
+        let mut env = Self::default();
+        env.set("ALMA_ORDER", "A B");
+        env.set_slot(Slot::A, true, false);
+        env.set_slot(Slot::B, false, false);
+        env
+    }
+
+    pub fn parse(block: &str) -> Self {
+        let variables = block
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+            .map(|(name, value)| (String::from(name), String::from(value)))
+            .collect();
+        Self { variables }
+    }
+
+    pub fn to_block(&self) -> anyhow::Result<String> {
+        let mut block = String::from(GRUBENV_HEADER);
+        for (name, value) in &self.variables {
+            writeln!(block, "{}={}", name, value)?;
+        }
+        if block.len() > GRUBENV_SIZE {
+            return Err(anyhow!("The GRUB environment block is full"));
+        }
+        block.push_str(&"#".repeat(GRUBENV_SIZE - block.len()));
+        Ok(block)
+    }
+
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        fs::read_to_string(path)
+            .map(|block| Self::parse(&block))
+            .with_context(|| format!("Error reading {}", path.display()))
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, self.to_block()?)
+            .with_context(|| format!("Error writing {}", path.display()))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.variables
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) {
+        match self.variables.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) => *v = String::from(value),
+            None => self
+                .variables
+                .push((String::from(name), String::from(value))),
+        }
+    }
+
+    fn flag(&self, slot: Slot, flag: &str) -> bool {
+        self.get(&format!("ALMA_{}_{}", slot.name(), flag)) == Some("1")
+    }
+
+    pub fn set_slot(&mut self, slot: Slot, ok: bool, tried: bool) {
+        let value = |flag: bool| if flag { "1" } else { "0" };
+        self.set(&format!("ALMA_{}_OK", slot.name()), value(ok));
+        self.set(&format!("ALMA_{}_TRY", slot.name()), value(tried));
+    }
+
+    fn order(&self) -> Vec<Slot> {
+        self.get("ALMA_ORDER")
+            .unwrap_or("A B")
+            .split_whitespace()
+            .filter_map(Slot::from_name)
+            .collect()
+    }
+
+    /// The slot GRUB boots, the same way the GRUB configuration picks it
+    pub fn active_slot(&self) -> Slot {
+        let order = self.order();
+        order
+            .iter()
+            .copied()
+            .find(|slot| self.flag(*slot, "OK") && !self.flag(*slot, "TRY"))
+            .or_else(|| order.first().copied())
+            .unwrap_or(Slot::A)
+    }
+
+    /// Boots the given slot next, with the other one as the fallback
+    pub fn switch_to(&mut self, slot: Slot) {
+        self.set(
+            "ALMA_ORDER",
+            &format!("{} {}", slot.name(), slot.other().name()),
+        );
+        self.set_slot(slot, true, false);
+    }
+}
+
+/// Finds the two root partitions of a device with A/B slots, slot A first
+pub fn slot_partitions(partitions: &[(PartitionType, Partition)]) -> Option<[usize; 2]> {
+    let roots: Vec<usize> = partitions
+        .iter()
+        .enumerate()
+        .filter(|(_, (partition_type, _))| *partition_type == PartitionType::LinuxRoot)
+        .map(|(i, _)| i)
+        .collect();
+    match roots.as_slice() {
+        [a, b] => Some([*a, *b]),
+        _ => None,
+    }
+}
+
+/// Reads the slot state from the EFI system partition of a device with A/B slots
+pub fn read_env(esp: &Filesystem) -> anyhow::Result<GrubEnv> {
+    let mount_point = tempdir().context("Error creating a temporary directory")?;
+    let mut mount_stack = MountStack::new();
+    mount_stack
+        .mount(esp, mount_point.path().to_path_buf(), None)
+        .context("Error mounting the EFI system partition")?;
+    let env = GrubEnv::read(&mount_point.path().join(GRUBENV_PATH));
+    mount_stack.umount()?;
+    env
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grubenv_round_trip() {
+        let block = GrubEnv::initial().to_block().unwrap();
+        assert_eq!(block.len(), GRUBENV_SIZE);
+        assert!(block.starts_with("# GRUB Environment Block\nALMA_ORDER=A B\nALMA_A_OK=1\n"));
+        assert_eq!(GrubEnv::parse(&block), GrubEnv::initial());
+    }
+
+    #[test]
+    fn unconfirmed_slot_falls_back() {
+        let mut env = GrubEnv::initial();
+        assert_eq!(env.active_slot(), Slot::A);
+
+        env.switch_to(Slot::B);
+        assert_eq!(env.get("ALMA_ORDER"), Some("B A"));
+        assert_eq!(env.active_slot(), Slot::B);
+
+        // GRUB booted slot B, which never confirmed the boot
+        env.set("ALMA_B_TRY", "1");
+        assert_eq!(env.active_slot(), Slot::A);
+
+        // Neither slot boots, so GRUB tries the preferred one
+        env.set("ALMA_A_TRY", "1");
+        assert_eq!(env.active_slot(), Slot::B);
+    }
+}
@@ -1,12 +1,39 @@
 use super::aur::AurHelper;
+use super::bootloader::Bootloader;
+use super::export::{Compression, ImageFormat};
+use super::storage::{FilesystemType, KeySource, LuksOptions, LuksType, PartitionTableType, Pbkdf};
+use anyhow::anyhow;
 use byte_unit::Byte;
 use std::path::PathBuf;
+use std::str::FromStr;
 use structopt::StructOpt;
 
 /// Parse size argument as bytes
 /// e.g. 10GB, 10GiB, etc.
 fn parse_bytes(src: &str) -> Result<Byte, &'static str> {
-    Byte::from_str(src).map_err(|_| "Invalid image size")
+    Byte::from_str(src).map_err(|_| "Invalid size")
+}
+
+/// Size of the exFAT data partition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataSize {
+    Megabytes(u32),
+    /// Everything the other partitions leave
+    Rest,
+}
+
+impl FromStr for DataSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "rest" => Ok(DataSize::Rest),
+            _ => s
+                .parse()
+                .map(DataSize::Megabytes)
+                .map_err(|_| anyhow!("Invalid data partition size: {}", s)),
+        }
+    }
 }
 
 #[derive(StructOpt)]
@@ -21,6 +48,7 @@ pub struct App {
 }
 
 #[derive(StructOpt)]
+#[allow(clippy::large_enum_variant)] // Parsed once, not worth boxing
 pub enum Command {
     #[structopt(name = "create", about = "Create a new Arch Linux USB")]
     Create(CreateCommand),
@@ -30,6 +58,27 @@ pub enum Command {
 
     #[structopt(name = "qemu", about = "Boot the USB with Qemu")]
     Qemu(QemuCommand),
+
+    #[structopt(name = "luks", about = "Manage the keys of an encrypted Live USB")]
+    Luks(LuksCommand),
+
+    #[structopt(name = "shrink", about = "Shrink an image to the size of its data")]
+    Shrink(ShrinkCommand),
+
+    #[structopt(name = "flash", about = "Write an image to a USB drive and verify it")]
+    Flash(FlashCommand),
+
+    #[structopt(
+        name = "sign",
+        about = "Sign the bootloader and kernel images of a USB for Secure Boot"
+    )]
+    Sign(SignCommand),
+
+    #[structopt(
+        name = "update",
+        about = "Update the inactive slot of an A/B USB and boot it next"
+    )]
+    Update(UpdateCommand),
 }
 
 #[derive(StructOpt)]
@@ -52,10 +101,157 @@ pub struct CreateCommand {
     #[structopt(long = "aur-packages", value_name = "aurpackage")]
     pub aur_packages: Vec<String>,
 
-    /// Boot partition size in megabytes
+    /// Kernel package to install, such as linux-lts or linux-zen. Defaults to linux
+    ///
+    /// Can be given more than once. The first kernel is booted by default, and all of them get boot
+    /// entries.
+    #[structopt(long = "kernel", value_name = "package")]
+    pub kernels: Vec<String>,
+
+    /// Kernel parameter to boot with, such as quiet or console=ttyS0
+    ///
+    /// Can be given more than once. Parameters are added after those of the presets.
+    #[structopt(long = "kernel-parameter", value_name = "parameter")]
+    pub kernel_parameters: Vec<String>,
+
+    /// Boot partition size in megabytes when using the default partition layout
     #[structopt(long = "boot-size")]
     pub boot_size: Option<u32>,
 
+    /// Add an exFAT data partition in front of the others, which Windows and macOS can read
+    ///
+    /// The size is in megabytes, or "rest" for everything the other partitions leave, which
+    /// requires --root-size. Only available with the default partition layout.
+    #[structopt(long = "data-size", value_name = "megabytes|rest")]
+    pub data_size: Option<DataSize>,
+
+    /// Where the data partition is mounted in the installed system
+    #[structopt(long = "data-mount-point", requires = "data-size")]
+    pub data_mount_point: Option<PathBuf>,
+
+    /// Size of the root partition when the data partition takes the rest of the disk, and of each
+    /// slot with --ab
+    #[structopt(long = "root-size", parse(try_from_str = parse_bytes))]
+    pub root_size: Option<Byte>,
+
+    /// Root filesystem when using the default partition layout
+    ///
+    /// A btrfs root filesystem is created with the @, @home, @var_log and @snapshots subvolumes.
+    /// f2fs is gentler on cheap flash storage and is created with compression enabled
+    #[structopt(long = "root-filesystem", possible_values = &["ext4", "btrfs", "f2fs"])]
+    pub root_filesystem: Option<FilesystemType>,
+
+    /// Partition table type
+    ///
+    /// msdos creates a legacy MBR partition table for old BIOS machines which refuse to boot
+    /// GPT disks. GRUB is installed for both BIOS and UEFI.
+    #[structopt(
+        long = "partition-table",
+        default_value = "gpt",
+        possible_values = &["gpt", "msdos"]
+    )]
+    pub partition_table: PartitionTableType,
+
+    /// Bootloader to install
+    ///
+    /// systemd-boot only boots on UEFI machines and needs the EFI system partition mounted at
+    /// /boot. It is kept up to date by a pacman hook.
+    #[structopt(
+        long = "bootloader",
+        default_value = "grub",
+        possible_values = &["grub", "systemd-boot"]
+    )]
+    pub bootloader: Bootloader,
+
+    /// Boot a unified kernel image, which bundles the kernel, the initramfs, the microcode and the
+    /// kernel command line. Requires systemd-boot
+    #[structopt(long = "uki")]
+    pub uki: bool,
+
+    /// Private key in PEM format which signs systemd-boot and the unified kernel image for
+    /// Secure Boot, instead of setting up shim
+    ///
+    /// Use a db key whose certificate is enrolled in the firmware. The key stays on this machine,
+    /// so the images have to be signed again with alma sign after kernel updates, unless
+    /// --secure-boot-hook-keys installs a signing hook.
+    #[structopt(
+        long = "secure-boot-key",
+        value_name = "key",
+        requires_all = &["uki", "secure-boot-cert"]
+    )]
+    pub secure_boot_key: Option<PathBuf>,
+
+    /// Certificate in PEM format which belongs to --secure-boot-key
+    #[structopt(
+        long = "secure-boot-cert",
+        value_name = "certificate",
+        requires = "secure-boot-key"
+    )]
+    pub secure_boot_cert: Option<PathBuf>,
+
+    /// Directory of the installed system with db.key and db.crt, which a pacman hook signs rebuilt
+    /// unified kernel images and systemd-boot updates with
+    ///
+    /// ALMA does not copy the keys there. Without them the hook only warns. Anyone who can read
+    /// them on the device can sign boot files for every machine which trusts the certificate.
+    #[structopt(
+        long = "secure-boot-hook-keys",
+        value_name = "directory",
+        requires = "secure-boot-key",
+        parse(from_os_str)
+    )]
+    pub secure_boot_hook_keys: Option<PathBuf>,
+
+    /// Grow the root partition and its filesystem to fill the device on first boot
+    ///
+    /// Useful for small images which are flashed to larger sticks. The root partition must be the
+    /// last one.
+    #[structopt(long = "grow-root")]
+    pub grow_root: bool,
+
+    /// Mount the root filesystem read-only and keep all changes in a tmpfs overlay
+    ///
+    /// Nothing is written to the root filesystem while the system runs. Boot with the kernel
+    /// parameter alma_overlay=0 to make persistent changes.
+    #[structopt(
+        long = "overlay-root",
+        conflicts_with = "snapper",
+        conflicts_with = "grow-root"
+    )]
+    pub overlay_root: bool,
+
+    /// Add a boot menu entry which copies the root filesystem to RAM, so the device can be removed
+    /// after booting
+    #[structopt(long = "toram", conflicts_with = "lvm")]
+    pub toram: bool,
+
+    /// Create two root partitions of --root-size each (A/B slots), which alma update installs
+    /// updates into in turn
+    ///
+    /// GRUB boots the slot updated last and falls back to the other one if the system does not
+    /// come up. Requires a GPT partition table.
+    #[structopt(
+        long = "ab",
+        requires = "root-size",
+        conflicts_with_all = &["encrypted-root", "grow-root"]
+    )]
+    pub ab: bool,
+
+    /// Take periodic snapshots of the btrfs root filesystem with snapper and add them to the boot
+    /// menu with grub-btrfs
+    #[structopt(long = "snapper")]
+    pub snapper: bool,
+
+    /// Build the initramfs around systemd instead of busybox
+    ///
+    /// An encrypted root is unlocked by sd-encrypt from /etc/crypttab.initramfs. The busybox hooks
+    /// of --overlay-root, --toram and --snapper are not available.
+    #[structopt(
+        long = "systemd-initramfs",
+        conflicts_with_all = &["overlay-root", "toram", "snapper"]
+    )]
+    pub systemd_initramfs: bool,
+
     /// Enter interactive chroot before unmounting the drive
     #[structopt(short = "i", long = "interactive")]
     pub interactive: bool,
@@ -64,6 +260,91 @@ pub struct CreateCommand {
     #[structopt(short = "e", long = "encrypted-root")]
     pub encrypted_root: bool,
 
+    /// Where the passphrase of the encrypted root partition comes from
+    ///
+    /// One of prompt, stdin, env:VAR, file:PATH or generate[:PATH]. A generated random key file is
+    /// stored next to the image unless a path is given.
+    #[structopt(long = "luks-key", value_name = "source", requires = "encrypted-root")]
+    pub luks_key: Option<KeySource>,
+
+    /// LUKS format of the encrypted root partition
+    #[structopt(long = "luks-type", possible_values = &["luks1", "luks2"], requires = "encrypted-root")]
+    pub luks_type: Option<LuksType>,
+
+    /// Cipher of the encrypted root partition, e.g. aes-xts-plain64
+    #[structopt(
+        long = "luks-cipher",
+        value_name = "cipher",
+        requires = "encrypted-root"
+    )]
+    pub luks_cipher: Option<String>,
+
+    /// Key size of the encrypted root partition in bits
+    #[structopt(
+        long = "luks-key-size",
+        value_name = "bits",
+        requires = "encrypted-root"
+    )]
+    pub luks_key_size: Option<u32>,
+
+    /// Key derivation function of the encrypted root partition
+    #[structopt(
+        long = "luks-pbkdf",
+        possible_values = &["pbkdf2", "argon2i", "argon2id"],
+        requires = "encrypted-root"
+    )]
+    pub luks_pbkdf: Option<Pbkdf>,
+
+    /// Time in milliseconds spent deriving the key from the passphrase
+    #[structopt(
+        long = "luks-iter-time",
+        value_name = "ms",
+        requires = "encrypted-root"
+    )]
+    pub luks_iter_time: Option<u32>,
+
+    /// Memory cost of argon2 in KiB. Lower it for target machines with little RAM
+    #[structopt(
+        long = "luks-pbkdf-memory",
+        value_name = "KiB",
+        requires = "encrypted-root"
+    )]
+    pub luks_pbkdf_memory: Option<u32>,
+
+    /// Add a randomly generated recovery key to the second key slot of the encrypted root
+    /// partition
+    ///
+    /// The key is saved to the given file, or printed if no file is given
+    #[structopt(
+        long = "recovery-key",
+        value_name = "file",
+        requires = "encrypted-root"
+    )]
+    pub recovery_key: Option<Option<PathBuf>>,
+
+    /// Create an LVM volume group on the encrypted root partition, with separate logical volumes
+    /// for the root and home filesystems
+    #[structopt(long = "lvm", requires = "encrypted-root")]
+    pub lvm: bool,
+
+    /// Size of the root logical volume. Defaults to half of the volume group
+    #[structopt(
+        long = "lvm-root-size",
+        parse(try_from_str = parse_bytes),
+        value_name = "size",
+        requires = "lvm"
+    )]
+    pub lvm_root_size: Option<Byte>,
+
+    /// Add a swap logical volume of the given size
+    #[structopt(
+        long = "swap-size",
+        parse(try_from_str = parse_bytes),
+        value_name = "size",
+        requires = "lvm"
+    )]
+    pub swap_size: Option<Byte>,
+
     /// Path to preset files
     #[structopt(long = "presets", value_name = "preset")]
     pub presets: Vec<PathBuf>,
@@ -77,6 +358,25 @@ pub struct CreateCommand {
     )]
     pub image: Option<Byte>,
 
+    /// Also write a compressed copy of the image, a bmap file listing the blocks in use and a
+    /// SHA-256 manifest next to it
+    #[structopt(
+        long = "compress",
+        value_name = "compression",
+        possible_values = &["zstd", "xz"],
+        requires = "image"
+    )]
+    pub compress: Option<Compression>,
+
+    /// Also convert the image to a virtual machine disk next to it
+    #[structopt(
+        long = "image-format",
+        value_name = "format",
+        possible_values = &["qcow2", "vmdk", "vhdx"],
+        requires = "image"
+    )]
+    pub image_format: Option<ImageFormat>,
+
     /// Overwrite existing image files. Use with caution!
     #[structopt(long = "overwrite")]
     pub overwrite: bool,
@@ -92,6 +392,20 @@ pub struct CreateCommand {
     pub aur_helper: AurHelper,
 }
 
+impl CreateCommand {
+    /// LUKS options given in the command line
+    pub fn luks_options(&self) -> LuksOptions {
+        LuksOptions {
+            luks_type: self.luks_type,
+            cipher: self.luks_cipher.clone(),
+            key_size: self.luks_key_size,
+            pbkdf: self.luks_pbkdf,
+            iter_time: self.luks_iter_time,
+            pbkdf_memory: self.luks_pbkdf_memory,
+        }
+    }
+}
+
 #[derive(StructOpt)]
 pub struct ChrootCommand {
     /// Path starting with /dev/disk/by-id for the USB drive
@@ -102,14 +416,98 @@ pub struct ChrootCommand {
     #[structopt(long = "allow-non-removable")]
     pub allow_non_removable: bool,
 
+    /// Where the passphrase of an encrypted root partition comes from
+    ///
+    /// One of prompt, stdin, env:VAR or file:PATH
+    #[structopt(long = "luks-key", value_name = "source", default_value = "prompt")]
+    pub luks_key: KeySource,
+
     /// Optional command to run
     #[structopt()]
     pub command: Vec<String>,
 }
 
+#[derive(StructOpt)]
+pub struct SignCommand {
+    /// Path starting with /dev/disk/by-id for the USB drive, or an image file
+    #[structopt(parse(from_os_str))]
+    pub block_device: PathBuf,
+
+    /// Allow non-removable devices. Use with extreme caution!
+    #[structopt(long = "allow-non-removable")]
+    pub allow_non_removable: bool,
+
+    /// Private key in PEM format
+    #[structopt(long = "secure-boot-key", value_name = "key")]
+    pub secure_boot_key: PathBuf,
+
+    /// Certificate in PEM format which belongs to the key
+    #[structopt(long = "secure-boot-cert", value_name = "certificate")]
+    pub secure_boot_cert: PathBuf,
+}
+
+#[derive(StructOpt)]
+pub struct UpdateCommand {
+    /// Path starting with /dev/disk/by-id for the USB drive, or an image file
+    #[structopt(parse(from_os_str))]
+    pub block_device: PathBuf,
+
+    /// Allow non-removable devices. Use with extreme caution!
+    #[structopt(long = "allow-non-removable")]
+    pub allow_non_removable: bool,
+
+    /// Command which updates the copy in the inactive slot. Defaults to pacman -Syu
+    #[structopt()]
+    pub command: Vec<String>,
+}
+
+#[derive(StructOpt)]
+pub struct LuksCommand {
+    /// Path starting with /dev/disk/by-id for the USB drive, or an image file
+    #[structopt(parse(from_os_str))]
+    pub block_device: PathBuf,
+
+    /// Allow non-removable devices. Use with extreme caution!
+    #[structopt(long = "allow-non-removable")]
+    pub allow_non_removable: bool,
+
+    /// Where an existing passphrase comes from: prompt, stdin, env:VAR or file:PATH
+    ///
+    /// For remove-key, this is the key which gets removed
+    #[structopt(long = "luks-key", value_name = "source", default_value = "prompt")]
+    pub luks_key: KeySource,
+
+    #[structopt(subcommand)]
+    pub action: LuksAction,
+}
+
+#[derive(StructOpt)]
+pub enum LuksAction {
+    #[structopt(name = "add-key", about = "Add a passphrase or key file")]
+    Add {
+        /// Where the new key comes from: prompt, stdin, env:VAR, file:PATH or generate:PATH
+        #[structopt(long = "new-key", value_name = "source", default_value = "prompt")]
+        new_key: KeySource,
+
+        /// Key slot for the new key
+        #[structopt(long = "slot")]
+        slot: Option<u8>,
+    },
+
+    #[structopt(name = "change-key", about = "Replace a passphrase or key file")]
+    Change {
+        /// Where the new key comes from: prompt, stdin, env:VAR, file:PATH or generate:PATH
+        #[structopt(long = "new-key", value_name = "source", default_value = "prompt")]
+        new_key: KeySource,
+    },
+
+    #[structopt(name = "remove-key", about = "Remove a passphrase or key file")]
+    Remove,
+}
+
 #[derive(StructOpt)]
 pub struct QemuCommand {
-    /// Path starting with /dev/disk/by-id for the USB drive
+    /// Path starting with /dev/disk/by-id for the USB drive, or a raw, qcow2, vmdk or vhdx image
     #[structopt(parse(from_os_str))]
     pub block_device: PathBuf,
 
@@ -117,3 +515,57 @@ pub struct QemuCommand {
     #[structopt()]
     pub args: Vec<String>,
 }
+
+#[derive(StructOpt)]
+pub struct ShrinkCommand {
+    /// Image file with an ext4 root filesystem in its last partition
+    #[structopt(parse(from_os_str))]
+    pub image: PathBuf,
+
+    /// Where the passphrase of an encrypted root partition comes from
+    ///
+    /// One of prompt, stdin, env:VAR or file:PATH
+    #[structopt(long = "luks-key", value_name = "source", default_value = "prompt")]
+    pub luks_key: KeySource,
+
+    /// Trim the free space of the root filesystem first, so the image compresses better
+    #[structopt(long = "trim")]
+    pub trim: bool,
+}
+
+#[derive(StructOpt)]
+pub struct FlashCommand {
+    /// A raw, zstd or xz compressed image
+    #[structopt(parse(from_os_str))]
+    pub image: PathBuf,
+
+    /// Paths starting with /dev/disk/by-id for the USB drives, written in parallel. Prompts for
+    /// one if omitted
+    #[structopt(parse(from_os_str))]
+    pub block_devices: Vec<PathBuf>,
+
+    /// Write to all inserted removable devices
+    ///
+    /// Never picks non-removable devices, so the disks of the host are safe.
+    #[structopt(
+        long = "all",
+        conflicts_with_all = &["block-devices", "allow-non-removable"]
+    )]
+    pub all: bool,
+
+    /// Block map of the image. Defaults to the .bmap file next to the image, if it exists
+    #[structopt(long = "bmap", value_name = "file", parse(from_os_str))]
+    pub bmap: Option<PathBuf>,
+
+    /// Write the whole image even if a block map exists
+    #[structopt(long = "no-bmap", conflicts_with = "bmap")]
+    pub no_bmap: bool,
+
+    /// Do not read the data back after writing it
+    #[structopt(long = "no-verify")]
+    pub no_verify: bool,
+
+    /// Allow non-removable devices. Use with extreme caution!
+    #[structopt(long = "allow-non-removable")]
+    pub allow_non_removable: bool,
+}
@@ -0,0 +1,186 @@
+use anyhow::{anyhow, Context};
+use sha2::{Digest, Sha256};
+
+/// Placeholder for the checksum of the bmap file itself, which is computed with this value in
+/// place of the real checksum
+const CHECKSUM_PLACEHOLDER: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// An inclusive range of blocks which hold data, with the SHA-256 of their contents
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BmapRange {
+    pub first: u64,
+    pub last: u64,
+    pub checksum: String,
+}
+
+/// A block map (version 2.0, as understood by bmaptool) listing the blocks of an image in use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bmap {
+    pub image_size: u64,
+    pub block_size: u64,
+    pub ranges: Vec<BmapRange>,
+}
+
+impl Bmap {
+    /// The byte range of a block range. The last block of the image may be partial
+    pub fn byte_range(&self, range: &BmapRange) -> (u64, u64) {
+        (
+            range.first * self.block_size,
+            ((range.last + 1) * self.block_size).min(self.image_size),
+        )
+    }
+
+    pub fn mapped_bytes(&self) -> u64 {
+        self.ranges
+            .iter()
+            .map(|range| {
+                let (start, end) = self.byte_range(range);
+                end - start
+            })
+            .sum()
+    }
+
+    pub fn to_xml(&self) -> String {
+        let blocks = self.image_size.div_ceil(self.block_size);
+        let mapped_blocks: u64 = self.ranges.iter().map(|r| r.last - r.first + 1).sum();
+
+        let block_map: String = self
+            .ranges
+            .iter()
+            .map(|r| {
+                let range = if r.first == r.last {
+                    r.first.to_string()
+                } else {
+                    format!("{}-{}", r.first, r.last)
+                };
+                format!(
+                    "        <Range chksum=\"{}\"> {} </Range>\n",
+                    r.checksum, range
+                )
+            })
+            .collect();
+
+        let bmap = format!(
+            "<?xml version=\"1.0\" ?>
+<bmap version=\"2.0\">
+    <ImageSize> {} </ImageSize>
+    <BlockSize> {} </BlockSize>
+    <BlocksCount> {} </BlocksCount>
+    <MappedBlocksCount> {} </MappedBlocksCount>
+    <ChecksumType> sha256 </ChecksumType>
+    <BmapFileChecksum> {} </BmapFileChecksum>
+    <BlockMap>
+{}    </BlockMap>
+</bmap>
+",
+            self.image_size,
+            self.block_size,
+            blocks,
+            mapped_blocks,
+            CHECKSUM_PLACEHOLDER,
+            block_map
+        );
+
+        let checksum = format!("{:x}", Sha256::digest(bmap.as_bytes()));
+        bmap.replace(CHECKSUM_PLACEHOLDER, &checksum)
+    }
+
+    /// Parses a bmap file with SHA-256 checksums and verifies its own checksum
+    /// The file is line based in practice, so no XML parser is needed.
+    pub fn from_xml(xml: &str) -> anyhow::Result<Self> {
+        let element = |name: &str| -> anyhow::Result<&str> {
+            let open = format!("<{}>", name);
+            let close = format!("</{}>", name);
+            xml.lines()
+                .find_map(|line| {
+                    line.trim()
+                        .strip_prefix(open.as_str())
+                        .and_then(|rest| rest.strip_suffix(close.as_str()))
+                })
+                .map(str::trim)
+                .ok_or_else(|| anyhow!("The block map has no {}", name))
+        };
+        let number = |name: &str| -> anyhow::Result<u64> {
+            element(name)?
+                .parse()
+                .with_context(|| format!("Invalid {} in the block map", name))
+        };
+
+        if element("ChecksumType")? != "sha256" {
+            return Err(anyhow!(
+                "Only block maps with sha256 checksums are supported"
+            ));
+        }
+
+        let checksum = element("BmapFileChecksum")?;
+        let unchecked = xml.replacen(checksum, CHECKSUM_PLACEHOLDER, 1);
+        if format!("{:x}", Sha256::digest(unchecked.as_bytes())) != checksum {
+            return Err(anyhow!("The block map is corrupted"));
+        }
+
+        let ranges = xml
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("<Range chksum=\""))
+            .map(|line| {
+                let (checksum, rest) = line
+                    .split_once("\">")
+                    .ok_or_else(|| anyhow!("Invalid range in the block map: {}", line))?;
+                let blocks = rest
+                    .strip_suffix("</Range>")
+                    .ok_or_else(|| anyhow!("Invalid range in the block map: {}", line))?
+                    .trim();
+                let (first, last) = blocks.split_once('-').unwrap_or((blocks, blocks));
+                Ok(BmapRange {
+                    first: first.parse().context("Invalid range in the block map")?,
+                    last: last.parse().context("Invalid range in the block map")?,
+                    checksum: String::from(checksum),
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            image_size: number("ImageSize")?,
+            block_size: number("BlockSize")?,
+            ranges,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xml_round_trip() {
+        let bmap = Bmap {
+            image_size: 10000,
+            block_size: 4096,
+            ranges: vec![
+                BmapRange {
+                    first: 0,
+                    last: 1,
+                    checksum: String::from("abc"),
+                },
+                BmapRange {
+                    first: 2,
+                    last: 2,
+                    checksum: String::from("def"),
+                },
+            ],
+        };
+
+        let xml = bmap.to_xml();
+        assert!(xml.contains("<BlocksCount> 3 </BlocksCount>"));
+        assert!(xml.contains("<MappedBlocksCount> 3 </MappedBlocksCount>"));
+        assert!(xml.contains("<Range chksum=\"abc\"> 0-1 </Range>"));
+        assert!(xml.contains("<Range chksum=\"def\"> 2 </Range>"));
+
+        let parsed = Bmap::from_xml(&xml).unwrap();
+        assert_eq!(parsed, bmap);
+        assert_eq!(parsed.mapped_bytes(), 10000);
+
+        let corrupted = xml.replace("0-1", "0-3");
+        assert!(Bmap::from_xml(&corrupted).is_err());
+    }
+}
@@ -0,0 +1,270 @@
+use anyhow::anyhow;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bootloader {
+    Grub,
+    SystemdBoot,
+}
+
+impl FromStr for Bootloader {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "grub" => Ok(Bootloader::Grub),
+            "systemd-boot" => Ok(Bootloader::SystemdBoot),
+            _ => Err(anyhow!("Unknown bootloader: {}", s)),
+        }
+    }
+}
+
+/// The kernel command line of the systemd-boot entries, relative to the root
+pub const CMDLINE_PATH: &str = "etc/kernel/cmdline";
+pub const ENTRIES_SCRIPT_PATH: &str = "usr/local/bin/alma-loader-entries";
+pub const ENTRIES_HOOK_PATH: &str = "etc/pacman.d/hooks/90-alma-loader-entries.hook";
+
+/// Writes a systemd-boot entry for every installed kernel, with the command line from
+/// /etc/kernel/cmdline
+/// Runs at installation and from a pacman hook whenever kernels or microcode change.
+pub static ENTRIES_SCRIPT: &str = r#"#!/bin/bash
+set -euo pipefail
+
+entries=/boot/loader/entries
+cmdline=$(cat /etc/kernel/cmdline)
+mkdir -p "${entries}"
+rm -f "${entries}"/alma-*.conf
+
+microcode=""
+for image in intel-ucode.img amd-ucode.img; do
+    if [ -e "/boot/${image}" ]; then
+        microcode+="initrd  /${image}"$'\n'
+    fi
+done
+
+entry() {
+    cat >"${entries}/alma-$1.conf" <<EOF
+title   $2
+linux   /vmlinuz-${kernel}
+${microcode}initrd  /$3
+options $4
+EOF
+}
+
+default=""
+for pkgbase in /usr/lib/modules/*/pkgbase; do
+    [ -e "${pkgbase}" ] || continue
+    kernel=$(cat "${pkgbase}")
+    [ -e "/boot/vmlinuz-${kernel}" ] || continue
+    if [ -z "${default}" ]; then
+        default=${kernel}
+    fi
+
+    entry "${kernel}" "Arch Linux (${kernel})" "initramfs-${kernel}.img" "${cmdline}"
+    if [ -e "/boot/initramfs-${kernel}-fallback.img" ]; then
+        entry "${kernel}-fallback" "Arch Linux (${kernel}, fallback initramfs)" \
+            "initramfs-${kernel}-fallback.img" "${cmdline}"
+    fi
+    if [ -e /etc/initcpio/hooks/alma-toram ]; then
+        entry "${kernel}-toram" "Arch Linux (${kernel}, copy to RAM)" \
+            "initramfs-${kernel}.img" "${cmdline} alma_toram=1"
+    fi
+done
+
+# Keeps the default entry unless its kernel was removed
+current=$(sed -n 's/^default //p' /boot/loader/loader.conf 2>/dev/null || true)
+if [ -n "${default}" ] && { [ -z "${current}" ] || [ ! -e "${entries}/${current}" ]; }; then
+    printf 'timeout 3\ndefault alma-%s.conf\n' "${default}" >/boot/loader/loader.conf
+fi
+"#;
+
+pub static ENTRIES_HOOK: &str = "[Trigger]
+Type = Path
+Operation = Install
+Operation = Upgrade
+Operation = Remove
+Target = usr/lib/modules/*/pkgbase
+Target = boot/*-ucode.img
+
+[Action]
+Description = Updating systemd-boot entries...
+When = PostTransaction
+Exec = /usr/local/bin/alma-loader-entries
+";
+
+/// The mkinitcpio preset which builds a unified kernel image for the kernel package instead of a
+/// separate initramfs
+/// The kernel command line comes from /etc/kernel/cmdline and the microcode from the microcode
+/// hook. There is no fallback image, since without the autodetect hook it would be the same.
+pub fn uki_preset(kernel: &str) -> String {
+    format!(
+        "# Written by ALMA to build a unified kernel image
+ALL_kver=\"/boot/vmlinuz-{0}\"
+PRESETS=('default')
+default_uki=\"/boot/EFI/Linux/arch-{0}.efi\"
+",
+        kernel
+    )
+}
+
+/// The entry of a kernel as systemd-boot names it, which differs for unified kernel images
+pub fn entry_name(kernel: &str, uki: bool) -> String {
+    if uki {
+        format!("arch-{}.efi", kernel)
+    } else {
+        format!("alma-{}.conf", kernel)
+    }
+}
+
+pub fn loader_conf(default_entry: &str) -> String {
+    format!("timeout 3\ndefault {}\n", default_entry)
+}
+
+/// Kernel parameters in the order they were first given, without duplicates
+/// Only identical parameters are dropped, since some like console= may legitimately repeat.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KernelParameters(Vec<String>);
+
+impl KernelParameters {
+    /// Splits an existing command line, e.g. one written by a preset script
+    pub fn parse(cmdline: &str) -> Self {
+        let mut parameters = Self::default();
+        parameters.extend(cmdline.split_whitespace());
+        parameters
+    }
+
+    pub fn add(&mut self, parameter: &str) {
+        let parameter = parameter.trim();
+        if !parameter.is_empty() && !self.0.iter().any(|p| p == parameter) {
+            self.0.push(parameter.to_owned());
+        }
+    }
+
+    pub fn extend<'a>(&mut self, parameters: impl IntoIterator<Item = &'a str>) {
+        for parameter in parameters {
+            self.add(parameter);
+        }
+    }
+
+    /// Adds the parameters of `other` after these
+    pub fn merge(&mut self, other: &KernelParameters) {
+        self.extend(other.0.iter().map(String::as_str));
+    }
+}
+
+impl fmt::Display for KernelParameters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.join(" "))
+    }
+}
+
+/// The kernel parameter which unlocks the LUKS container with the given UUID
+pub fn luks_parameter(luks_uuid: &str) -> String {
+    format!("cryptdevice=UUID={}:luks_root", luks_uuid)
+}
+
+/// The kernel command line which boots the root filesystem with the given UUID, followed by the
+/// extra parameters
+pub fn kernel_cmdline(root_uuid: &str, extra: &KernelParameters) -> KernelParameters {
+    let mut cmdline = KernelParameters::default();
+    cmdline.add(&format!("root=UUID={}", root_uuid));
+    cmdline.add("rw");
+    cmdline.merge(extra);
+    cmdline
+}
+
+/// The unquoted value of a variable in /etc/default/grub
+pub fn grub_default(defaults: &str, name: &str) -> Option<String> {
+    let prefix = format!("{}=", name);
+    defaults
+        .lines()
+        .rev()
+        .find_map(|line| line.trim().strip_prefix(&prefix))
+        .map(|value| {
+            let value = value.trim();
+            for quote in &['"', '\''] {
+                if let Some(inner) = value
+                    .strip_prefix(*quote)
+                    .and_then(|v| v.strip_suffix(*quote))
+                {
+                    return inner.to_owned();
+                }
+            }
+            value.to_owned()
+        })
+}
+
+/// Sets a variable in /etc/default/grub, replacing earlier assignments instead of adding another
+/// one after them
+pub fn set_grub_default(defaults: &str, name: &str, value: &str) -> String {
+    let prefix = format!("{}=", name);
+    let assignment = format!("{}{}", prefix, shell_quote(value));
+    let mut replaced = false;
+    let mut lines: Vec<String> = Vec::new();
+    for line in defaults.lines() {
+        if line.trim().starts_with(&prefix) {
+            if !replaced {
+                lines.push(assignment.clone());
+                replaced = true;
+            }
+        } else {
+            lines.push(line.to_owned());
+        }
+    }
+    if !replaced {
+        lines.push(assignment);
+    }
+
+    let mut defaults = lines.join("\n");
+    defaults.push('\n');
+    defaults
+}
+
+/// Quotes a value for the shell which sources /etc/default/grub
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypted_cmdline() {
+        let mut extra = KernelParameters::default();
+        extra.add(&luks_parameter("abcd"));
+        extra.merge(&KernelParameters::parse("quiet rw console=ttyS0"));
+        assert_eq!(
+            kernel_cmdline("1234", &extra).to_string(),
+            "root=UUID=1234 rw cryptdevice=UUID=abcd:luks_root quiet console=ttyS0"
+        );
+        assert_eq!(
+            kernel_cmdline("1234", &KernelParameters::default()).to_string(),
+            "root=UUID=1234 rw"
+        );
+    }
+
+    #[test]
+    fn grub_defaults() {
+        let defaults = "GRUB_CMDLINE_LINUX_DEFAULT=\"loglevel=3 quiet\"\n\
+                        GRUB_CMDLINE_LINUX=\"\"\n\
+                        #GRUB_DISABLE_RECOVERY=true\n\
+                        GRUB_CMDLINE_LINUX=\"nomodeset\"\n";
+        assert_eq!(
+            grub_default(defaults, "GRUB_CMDLINE_LINUX").as_deref(),
+            Some("nomodeset")
+        );
+        assert_eq!(grub_default(defaults, "GRUB_TOP_LEVEL"), None);
+
+        let defaults = set_grub_default(defaults, "GRUB_CMDLINE_LINUX", "nomodeset it's");
+        let defaults = set_grub_default(&defaults, "GRUB_TOP_LEVEL", "/boot/vmlinuz-linux");
+        assert_eq!(
+            defaults,
+            "GRUB_CMDLINE_LINUX_DEFAULT=\"loglevel=3 quiet\"\n\
+             GRUB_CMDLINE_LINUX='nomodeset it'\\''s'\n\
+             #GRUB_DISABLE_RECOVERY=true\n\
+             GRUB_TOP_LEVEL='/boot/vmlinuz-linux'\n"
+        );
+    }
+}
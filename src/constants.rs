@@ -1,5 +1,7 @@
-pub const BOOT_PARTITION_INDEX: u8 = 1;
-pub const ROOT_PARTITION_INDEX: u8 = 3;
+use std::time::Duration;
+
+/// How long to wait for the partitions of a freshly partitioned device to appear
+pub const PARTITION_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub static JOURNALD_CONF: &str = "
 [Journal]
@@ -7,9 +9,8 @@ Storage=volatile
 SystemMaxUse=16M
 ";
 
-pub const BASE_PACKAGES: [&str; 9] = [
+pub const BASE_PACKAGES: [&str; 8] = [
     "base",
-    "linux",
     "linux-firmware",
     "grub",
     "efibootmgr",
@@ -19,4 +20,25 @@ pub const BASE_PACKAGES: [&str; 9] = [
     "amd-ucode",
 ];
 
+/// Name of the volume group created on the root partition with --lvm
+pub const LVM_VOLUME_GROUP: &str = "alma";
+
+/// Installed when neither the command line nor a preset chooses a kernel
+pub const DEFAULT_KERNEL: &str = "linux";
+
+/// Provides growpart for --grow-root
+pub const GROW_ROOT_PACKAGES: [&str; 1] = ["cloud-guest-utils"];
+
+pub const SNAPPER_PACKAGES: [&str; 3] = ["snapper", "grub-btrfs", "inotify-tools"];
+
+pub const SNAPPER_ROOT_CONFIG: [(&str, &str); 7] = [
+    ("SUBVOLUME", "/"),
+    ("TIMELINE_CREATE", "yes"),
+    ("TIMELINE_LIMIT_HOURLY", "5"),
+    ("TIMELINE_LIMIT_DAILY", "7"),
+    ("TIMELINE_LIMIT_WEEKLY", "0"),
+    ("TIMELINE_LIMIT_MONTHLY", "0"),
+    ("TIMELINE_LIMIT_YEARLY", "0"),
+];
+
 pub const AUR_DEPENDENCIES: [&str; 3] = ["base-devel", "git", "sudo"];
@@ -0,0 +1,280 @@
+use crate::bmap::{Bmap, BmapRange};
+use crate::process::CommandExt;
+use crate::tool::Tool;
+use anyhow::{anyhow, Context};
+use log::info;
+use nix::errno::Errno;
+use nix::unistd::{lseek, Whence};
+use sha2::{Digest, Sha256};
+use std::ffi::OsString;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const BMAP_BLOCK_SIZE: u64 = 4096;
+
+/// Compression of exported images
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Zstd,
+    Xz,
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zstd" => Ok(Compression::Zstd),
+            "xz" => Ok(Compression::Xz),
+            _ => Err(anyhow!("Unknown compression: {}", s)),
+        }
+    }
+}
+
+impl Compression {
+    fn tool_name(self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Compression::Zstd => "zst",
+            Compression::Xz => "xz",
+        }
+    }
+}
+
+/// Disk image formats of virtual machines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Raw,
+    Qcow2,
+    Vmdk,
+    Vhdx,
+}
+
+impl FromStr for ImageFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(ImageFormat::Raw),
+            "qcow2" => Ok(ImageFormat::Qcow2),
+            "vmdk" => Ok(ImageFormat::Vmdk),
+            "vhdx" => Ok(ImageFormat::Vhdx),
+            _ => Err(anyhow!("Unknown image format: {}", s)),
+        }
+    }
+}
+
+impl ImageFormat {
+    /// The name of the format for qemu and qemu-img, which is also used as the file extension
+    pub fn name(self) -> &'static str {
+        match self {
+            ImageFormat::Raw => "raw",
+            ImageFormat::Qcow2 => "qcow2",
+            ImageFormat::Vmdk => "vmdk",
+            ImageFormat::Vhdx => "vhdx",
+        }
+    }
+
+    fn from_header(header: &[u8]) -> Self {
+        if header.starts_with(b"QFI\xfb") {
+            ImageFormat::Qcow2
+        } else if header.starts_with(b"KDMV") || header.starts_with(b"# Disk DescriptorFile") {
+            ImageFormat::Vmdk
+        } else if header.starts_with(b"vhdxfile") {
+            ImageFormat::Vhdx
+        } else {
+            ImageFormat::Raw
+        }
+    }
+
+    /// Detects the format of an image file or block device by its header
+    pub fn detect(path: &Path) -> anyhow::Result<Self> {
+        let mut header = Vec::new();
+        fs::File::open(path)
+            .and_then(|file| file.take(32).read_to_end(&mut header))
+            .with_context(|| format!("Error reading {}", path.display()))?;
+        Ok(Self::from_header(&header))
+    }
+}
+
+/// Appends an extension to the file name, keeping the existing one (almatest.img.bmap)
+fn with_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+/// Writes a compressed copy of the image, a bmap file listing the blocks in use and a SHA-256
+/// manifest of both next to the image
+pub fn export(image: &Path, compression: Compression) -> anyhow::Result<()> {
+    let compressor = Tool::find(compression.tool_name())?;
+
+    info!("Creating the block map");
+    let bmap_path = with_extension(image, "bmap");
+    fs::write(&bmap_path, bmap(image)?.to_xml()).context("Error writing the block map")?;
+
+    let compressed_path = with_extension(image, compression.extension());
+    info!(
+        "Compressing the image to {} with {}",
+        compressed_path.display(),
+        compression.tool_name()
+    );
+    let compressed = fs::File::create(&compressed_path)
+        .with_context(|| format!("Error creating {}", compressed_path.display()))?;
+    compressor
+        .execute()
+        .args(["-T0", "-c"])
+        .arg(image)
+        .stdout(compressed)
+        .run()
+        .context("Error compressing the image")?;
+
+    info!("Writing the SHA-256 manifest");
+    let mut manifest = String::new();
+    for path in &[&compressed_path, &bmap_path] {
+        let mut file =
+            fs::File::open(path).with_context(|| format!("Error reading {}", path.display()))?;
+        let mut hasher = Sha256::new();
+        io::copy(&mut file, &mut hasher)
+            .with_context(|| format!("Error reading {}", path.display()))?;
+        writeln!(
+            manifest,
+            "{:x}  {}",
+            hasher.finalize(),
+            path.file_name()
+                .expect("Exported file has no name")
+                .to_string_lossy()
+        )?;
+    }
+    fs::write(with_extension(image, "sha256"), manifest)
+        .context("Error writing the SHA-256 manifest")?;
+
+    Ok(())
+}
+
+/// Converts the raw image to a virtual machine disk next to it (almatest.img.qcow2)
+pub fn convert(qemu_img: &Tool, image: &Path, format: ImageFormat) -> anyhow::Result<PathBuf> {
+    let converted_path = with_extension(image, format.name());
+    info!("Converting the image to {}", converted_path.display());
+    qemu_img
+        .execute()
+        .args(["convert", "-f", "raw", "-O", format.name()])
+        .arg(image)
+        .arg(&converted_path)
+        .run()
+        .context("Error converting the image")?;
+
+    Ok(converted_path)
+}
+
+/// Finds the byte ranges of the sparse file which hold data
+fn data_extents(file: &fs::File, size: u64) -> anyhow::Result<Vec<(u64, u64)>> {
+    let fd = file.as_raw_fd();
+    let mut extents = Vec::new();
+    let mut offset = 0;
+
+    while offset < size {
+        let start = match lseek(fd, offset as i64, Whence::SeekData) {
+            Ok(start) => start as u64,
+            // No data after the offset
+            Err(nix::Error::Sys(Errno::ENXIO)) => break,
+            Err(e) => return Err(e).context("Error looking for data in the image"),
+        };
+        let end = lseek(fd, start as i64, Whence::SeekHole)
+            .context("Error looking for holes in the image")? as u64;
+
+        extents.push((start, end));
+        offset = end;
+    }
+
+    Ok(extents)
+}
+
+/// Converts byte ranges into inclusive, merged ranges of blocks
+fn block_ranges(extents: &[(u64, u64)], block_size: u64) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+
+    for (start, end) in extents.iter().filter(|(start, end)| end > start) {
+        let first = start / block_size;
+        let last = (end - 1) / block_size;
+        match ranges.last_mut() {
+            Some((_, previous_last)) if first <= *previous_last + 1 => {
+                *previous_last = last.max(*previous_last)
+            }
+            _ => ranges.push((first, last)),
+        }
+    }
+
+    ranges
+}
+
+/// Creates the block map of the image
+fn bmap(image: &Path) -> anyhow::Result<Bmap> {
+    let mut file = fs::File::open(image).context("Error reading the image")?;
+    let size = file.metadata().context("Error reading the image")?.len();
+
+    let mut ranges = Vec::new();
+    let mut buffer = vec![0; BMAP_BLOCK_SIZE as usize];
+    for (first, last) in block_ranges(&data_extents(&file, size)?, BMAP_BLOCK_SIZE) {
+        let mut hasher = Sha256::new();
+        file.seek(SeekFrom::Start(first * BMAP_BLOCK_SIZE))
+            .context("Error reading the image")?;
+
+        let mut remaining = ((last + 1) * BMAP_BLOCK_SIZE).min(size) - first * BMAP_BLOCK_SIZE;
+        while remaining > 0 {
+            let chunk = &mut buffer[..remaining.min(BMAP_BLOCK_SIZE) as usize];
+            file.read_exact(chunk).context("Error reading the image")?;
+            hasher.update(&chunk);
+            remaining -= chunk.len() as u64;
+        }
+
+        ranges.push(BmapRange {
+            first,
+            last,
+            checksum: format!("{:x}", hasher.finalize()),
+        });
+    }
+
+    Ok(Bmap {
+        image_size: size,
+        block_size: BMAP_BLOCK_SIZE,
+        ranges,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_block_ranges() {
+        assert_eq!(
+            block_ranges(&[(0, 100), (4000, 8192), (20480, 20481)], 4096),
+            vec![(0, 1), (5, 5)]
+        );
+        assert_eq!(block_ranges(&[(0, 0)], 4096), vec![]);
+    }
+
+    #[test]
+    fn image_format_headers() {
+        assert_eq!(
+            ImageFormat::from_header(b"QFI\xfb\0\0\0\x03"),
+            ImageFormat::Qcow2
+        );
+        assert_eq!(ImageFormat::from_header(b"KDMV\x01"), ImageFormat::Vmdk);
+        assert_eq!(ImageFormat::from_header(b"vhdxfile"), ImageFormat::Vhdx);
+        assert_eq!(ImageFormat::from_header(&[0; 32]), ImageFormat::Raw);
+        assert_eq!(ImageFormat::from_header(b""), ImageFormat::Raw);
+    }
+}
@@ -0,0 +1,112 @@
+use crate::constants;
+use crate::storage::FilesystemType;
+use std::fmt::Write;
+
+pub const SERVICE_NAME: &str = "alma-grow-root.service";
+pub const SCRIPT_PATH: &str = "usr/local/bin/alma-grow-root";
+
+pub static SERVICE: &str = "[Unit]
+Description=Grow the root partition to fill the device
+After=local-fs.target
+
+[Service]
+Type=oneshot
+ExecStart=/usr/local/bin/alma-grow-root
+
+[Install]
+WantedBy=multi-user.target
+";
+
+/// A script run once on first boot, which grows the root partition to the end of the device and
+/// everything stacked on it: the LUKS container, the LVM physical volume and the filesystem
+pub struct GrowRoot {
+    part_uuid: String,
+    encrypted: bool,
+    lvm: bool,
+    filesystem: FilesystemType,
+}
+
+impl GrowRoot {
+    pub fn new(part_uuid: String, encrypted: bool, lvm: bool, filesystem: FilesystemType) -> Self {
+        Self {
+            part_uuid,
+            encrypted,
+            lvm,
+            filesystem,
+        }
+    }
+
+    pub fn to_script(&self) -> anyhow::Result<String> {
+        let mut output = format!(
+            "#!/bin/bash
+set -euo pipefail
+
+partition=$(readlink -f /dev/disk/by-partuuid/{})
+name=$(basename \"$partition\")
+disk=/dev/$(basename \"$(readlink -f \"/sys/class/block/$name/..\")\")
+number=$(cat \"/sys/class/block/$name/partition\")
+
+# growpart exits with 1 when there is no room left to grow into
+growpart \"$disk\" \"$number\" || [ $? -eq 1 ]
+",
+            self.part_uuid
+        );
+
+        // The root device the grown layers end up in
+        let mut device = String::from("\"$partition\"");
+
+        if self.encrypted {
+            // LUKS2 keeps the volume key in the kernel keyring, so resizing may need the passphrase
+            output.write_str(
+                "cryptsetup resize luks_root </dev/null ||
+    systemd-ask-password \"Passphrase to grow the root partition:\" |
+    cryptsetup resize --key-file=- luks_root
+",
+            )?;
+            device = String::from("/dev/mapper/luks_root");
+        }
+
+        // The home volume takes the rest of the volume group, so it gets the new space
+        // lvextend fails when there are no free extents, which is only the case when the partition
+        // did not grow
+        if self.lvm {
+            writeln!(
+                output,
+                "pvresize {device}
+if [ \"$(vgs --noheadings -o vg_free_count {group} | tr -d ' ')\" -gt 0 ]; then
+    lvextend -l +100%FREE {group}/home
+fi",
+                device = device,
+                group = constants::LVM_VOLUME_GROUP
+            )?;
+            device = format!("/dev/{}/home", constants::LVM_VOLUME_GROUP);
+        }
+
+        match self.filesystem {
+            FilesystemType::Btrfs => output.write_str("btrfs filesystem resize max /\n")?,
+            _ => writeln!(output, "resize2fs {}", device)?,
+        }
+
+        writeln!(output, "systemctl disable {}", SERVICE_NAME)?;
+
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypted_lvm_script() {
+        let script = GrowRoot::new(String::from("1234"), true, true, FilesystemType::Ext4)
+            .to_script()
+            .unwrap();
+        assert!(script.contains("/dev/disk/by-partuuid/1234"));
+        assert!(script.contains("pvresize /dev/mapper/luks_root"));
+        assert!(script.contains("vgs --noheadings -o vg_free_count alma"));
+        assert!(!script.contains("|| true"));
+        assert!(script.contains("resize2fs /dev/alma/home"));
+        assert!(script.ends_with("systemctl disable alma-grow-root.service\n"));
+    }
+}
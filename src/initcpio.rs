@@ -1,28 +1,354 @@
+use crate::storage::FilesystemType;
+use anyhow::Context;
+use serde::Deserialize;
 use std::fmt::Write;
+use std::fs;
+use std::path::Path;
 
+/// A mkinitcpio hook shipped with ALMA, which is installed into /etc/initcpio of the image
+pub struct Hook {
+    pub name: &'static str,
+    runtime: &'static str,
+    install: &'static str,
+}
+
+impl Hook {
+    pub fn install(&self, root: &Path) -> anyhow::Result<()> {
+        let initcpio_dir = root.join("etc/initcpio");
+        for (dir, content) in &[("hooks", self.runtime), ("install", self.install)] {
+            fs::create_dir_all(initcpio_dir.join(dir))
+                .and_then(|_| fs::write(initcpio_dir.join(dir).join(self.name), content))
+                .with_context(|| format!("Failed to install the {} hook", self.name))?;
+        }
+        Ok(())
+    }
+}
+
+/// Mounts the root filesystem read-only under a tmpfs overlay
+pub const OVERLAY_HOOK: Hook = Hook {
+    name: "alma-overlay",
+    runtime: r#"#!/usr/bin/ash
+
+run_latehook() {
+    # Booting with alma_overlay=0 writes to the root filesystem, for maintenance
+    if [ "${alma_overlay}" = "0" ]; then
+        return
+    fi
+
+    mkdir -p /run/alma/lower /run/alma/overlay
+    mount -t tmpfs -o mode=0755 alma-overlay /run/alma/overlay
+    mkdir -p /run/alma/overlay/upper /run/alma/overlay/work
+
+    mount --move /new_root /run/alma/lower
+    mount -o remount,ro /run/alma/lower
+    mount -t overlay overlay \
+        -o lowerdir=/run/alma/lower,upperdir=/run/alma/overlay/upper,workdir=/run/alma/overlay/work \
+        /new_root
+}
+"#,
+    install: r#"#!/bin/bash
+
+build() {
+    add_module overlay
+    add_runscript
+}
+
+help() {
+    cat <<HELPEOF
+Mounts the root filesystem read-only and keeps all changes in memory. Boot with alma_overlay=0
+to write to the root filesystem.
+HELPEOF
+}
+"#,
+};
+
+/// Copies the root filesystem to a tmpfs when booted with alma_toram=1, so the device can be
+/// removed afterwards
+pub const TORAM_HOOK: Hook = Hook {
+    name: "alma-toram",
+    runtime: r#"#!/usr/bin/ash
+
+run_latehook() {
+    if [ "${alma_toram}" != "1" ]; then
+        return
+    fi
+
+    local used available
+    used=$(df -k /new_root | awk 'NR == 2 { print $3 }')
+    available=$(awk '/^MemAvailable:/ { print $2 }' /proc/meminfo)
+    # Leave at least as much memory to the running system as the copy takes
+    if [ $((used * 2)) -gt "${available}" ]; then
+        echo "Not enough memory to copy the root filesystem (${used} KiB) to RAM. Booting from the device"
+        return
+    fi
+
+    echo "Copying the root filesystem (${used} KiB) to RAM"
+    mkdir -p /run/alma/ram
+    mount -t tmpfs -o mode=0755,size=$((available - used))k alma-toram /run/alma/ram
+    if ! cp -a /new_root/. /run/alma/ram/; then
+        echo "Copying the root filesystem to RAM failed. Booting from the device"
+        umount /run/alma/ram
+        return
+    fi
+
+    umount /new_root
+    mount --move /run/alma/ram /new_root
+}
+"#,
+    install: r#"#!/bin/bash
+
+build() {
+    add_runscript
+}
+
+help() {
+    cat <<HELPEOF
+Copies the root filesystem to RAM when booted with alma_toram=1, so the boot device can be
+removed.
+HELPEOF
+}
+"#,
+};
+
+/// GRUB configuration script which repeats the regular boot entries with alma_toram=1
+pub static TORAM_GRUB_SCRIPT: &str = r#"#!/bin/sh
+set -e
+
+GRUB_CMDLINE_LINUX="${GRUB_CMDLINE_LINUX} alma_toram=1" \
+    GRUB_DISABLE_SUBMENU=y \
+    GRUB_DISABLE_RECOVERY=true \
+    /etc/grub.d/10_linux |
+    sed -e "s/^menuentry '\([^']*\)'/menuentry '\1 (copy to RAM)'/" \
+        -e "s/\(\$menuentry_id_option '[^']*\)'/\1-toram'/"
+"#;
+
+/// The crypttab which sd-encrypt copies into the initramfs, relative to the root
+pub const CRYPTTAB_PATH: &str = "etc/crypttab.initramfs";
+pub const VCONSOLE_PATH: &str = "etc/vconsole.conf";
+
+/// The crypttab which unlocks the root partition with the given LUKS UUID as luks_root, asking for
+/// the passphrase
+pub fn crypttab(luks_uuid: &str) -> String {
+    format!("luks_root UUID={} none\n", luks_uuid)
+}
+
+/// Sets the console keymap in an existing vconsole.conf, keeping its other settings
+pub fn set_keymap(vconsole: &str, keymap: &str) -> String {
+    let mut output: String = vconsole
+        .lines()
+        .filter(|line| !line.trim().starts_with("KEYMAP="))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    output.push_str(&format!("KEYMAP={}\n", keymap));
+    output
+}
+
+/// Additions to the mkinitcpio configuration from presets
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct InitcpioOptions {
+    #[serde(default)]
+    pub modules: Vec<String>,
+    #[serde(default)]
+    pub binaries: Vec<String>,
+    #[serde(default)]
+    pub files: Vec<String>,
+    /// Hooks which go before the filesystems hook, like storage drivers
+    #[serde(default)]
+    pub hooks: Vec<String>,
+    pub compression: Option<String>,
+    /// Console keymap for typing the passphrase of an encrypted root
+    pub keymap: Option<String>,
+}
+
+impl InitcpioOptions {
+    /// Adds the lists of other after these. Its compression and keymap override these ones
+    pub fn merge(&mut self, other: &InitcpioOptions) {
+        self.modules.extend(other.modules.iter().cloned());
+        self.binaries.extend(other.binaries.iter().cloned());
+        self.files.extend(other.files.iter().cloned());
+        self.hooks.extend(other.hooks.iter().cloned());
+        if other.compression.is_some() {
+            self.compression = other.compression.clone();
+        }
+        if other.keymap.is_some() {
+            self.keymap = other.keymap.clone();
+        }
+    }
+}
+
+/// What the initramfs has to do before switching to the root filesystem
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Features {
+    /// Runs systemd instead of the busybox init, which has no runtime hooks
+    pub systemd: bool,
+    pub encrypted: bool,
+    pub lvm: bool,
+    pub snapshots: bool,
+    pub overlay: bool,
+    pub toram: bool,
+    /// Puts the microcode into the image, for unified kernel images which have no separate
+    /// microcode initrd
+    pub microcode: bool,
+}
+
+/// The mkinitcpio configuration
 pub struct Initcpio {
-    encrypted: bool,
+    modules: Vec<String>,
+    binaries: Vec<String>,
+    files: Vec<String>,
+    hooks: Vec<String>,
+    compression: Option<String>,
 }
 
-impl Initcpio {
-    pub fn new(encrypted: bool) -> Self {
-        Self { encrypted }
+/// Appends the items which are not in the list yet
+fn add_all<'a>(list: &mut Vec<String>, items: impl IntoIterator<Item = &'a str>) {
+    for item in items {
+        if !list.iter().any(|i| i == item) {
+            list.push(item.to_owned());
+        }
     }
+}
 
-    pub fn to_config(&self) -> anyhow::Result<String> {
-        let mut output = String::from(
-            "MODULES=()
-BINARIES=()
-FILES=()
-HOOKS=(base udev keyboard consolefont block ",
+impl Initcpio {
+    pub fn new(
+        features: Features,
+        root_filesystem: FilesystemType,
+        options: &InitcpioOptions,
+    ) -> Self {
+        let mut modules = Vec::new();
+        add_all(
+            &mut modules,
+            root_filesystem.initcpio_modules().iter().copied(),
         );
+        add_all(&mut modules, options.modules.iter().map(String::as_str));
 
-        if self.encrypted {
-            output.write_str("encrypt ")?;
+        let mut hooks = Vec::new();
+        if features.systemd {
+            add_all(&mut hooks, ["base", "systemd"]);
+        } else {
+            add_all(&mut hooks, ["base", "udev"]);
         }
+        if features.microcode {
+            add_all(&mut hooks, ["microcode"]);
+        }
+        if features.systemd {
+            add_all(&mut hooks, ["keyboard", "sd-vconsole", "block"]);
+        } else {
+            add_all(&mut hooks, ["keyboard", "keymap", "consolefont", "block"]);
+        }
+        if features.encrypted {
+            add_all(
+                &mut hooks,
+                [if features.systemd {
+                    "sd-encrypt"
+                } else {
+                    "encrypt"
+                }],
+            );
+        }
+        // Must come after encrypt, since the volume group lives inside the LUKS container
+        if features.lvm {
+            add_all(&mut hooks, ["lvm2"]);
+        }
+        add_all(&mut hooks, options.hooks.iter().map(String::as_str));
+        add_all(&mut hooks, ["filesystems", "fsck"]);
 
-        output.write_str("filesystems keyboard fsck)\n")?;
+        // Lets read-only snapshots booted from the GRUB menu start with a writable overlay
+        if features.snapshots {
+            add_all(&mut hooks, ["grub-btrfs-overlayfs"]);
+        }
+        // The copy in RAM becomes the lower layer of the overlay
+        if features.toram {
+            add_all(&mut hooks, [TORAM_HOOK.name]);
+        }
+        if features.overlay {
+            add_all(&mut hooks, [OVERLAY_HOOK.name]);
+        }
+
+        let mut binaries = Vec::new();
+        add_all(&mut binaries, options.binaries.iter().map(String::as_str));
+        let mut files = Vec::new();
+        add_all(&mut files, options.files.iter().map(String::as_str));
+
+        Self {
+            modules,
+            binaries,
+            files,
+            hooks,
+            compression: options.compression.clone(),
+        }
+    }
+
+    pub fn to_config(&self) -> anyhow::Result<String> {
+        let mut output = String::new();
+        for (name, list) in &[
+            ("MODULES", &self.modules),
+            ("BINARIES", &self.binaries),
+            ("FILES", &self.files),
+            ("HOOKS", &self.hooks),
+        ] {
+            writeln!(output, "{}=({})", name, list.join(" "))?;
+        }
+        if let Some(compression) = &self.compression {
+            writeln!(output, "COMPRESSION=\"{}\"", compression)?;
+        }
 
         Ok(output)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alma_hooks_come_last() {
+        let features = Features {
+            encrypted: true,
+            overlay: true,
+            toram: true,
+            ..Features::default()
+        };
+        let config = Initcpio::new(features, FilesystemType::Ext4, &InitcpioOptions::default())
+            .to_config()
+            .unwrap();
+        assert!(config.contains("HOOKS=(base udev keyboard keymap consolefont block encrypt "));
+        assert!(config.ends_with("fsck alma-toram alma-overlay)\n"));
+    }
+
+    #[test]
+    fn systemd_with_preset_hooks() {
+        let features = Features {
+            systemd: true,
+            encrypted: true,
+            lvm: true,
+            ..Features::default()
+        };
+        let options = InitcpioOptions {
+            modules: vec![String::from("crc32c_generic"), String::from("nvme")],
+            hooks: vec![String::from("lvm2"), String::from("plymouth")],
+            compression: Some(String::from("zstd")),
+            ..InitcpioOptions::default()
+        };
+        let config = Initcpio::new(features, FilesystemType::F2fs, &options)
+            .to_config()
+            .unwrap();
+        assert_eq!(
+            config,
+            "MODULES=(crc32_generic crc32c_generic nvme)
+BINARIES=()
+FILES=()
+HOOKS=(base systemd keyboard sd-vconsole block sd-encrypt lvm2 plymouth filesystems fsck)
+COMPRESSION=\"zstd\"
+"
+        );
+    }
+
+    #[test]
+    fn keymap_replaces_existing() {
+        assert_eq!(
+            set_keymap("KEYMAP=us\nFONT=ter-v16n\n", "de-latin1"),
+            "FONT=ter-v16n\nKEYMAP=de-latin1\n"
+        );
+    }
+}
@@ -1,32 +1,40 @@
+mod ab;
 mod args;
 mod aur;
+mod bmap;
+mod bootloader;
 mod constants;
+mod export;
+mod grow_root;
 mod initcpio;
 mod presets;
 mod process;
+mod secure_boot;
 mod storage;
 mod tool;
 
+use ab::Slot;
 use anyhow::{anyhow, Context};
 use args::Command;
+use bootloader::{Bootloader, KernelParameters};
 use byte_unit::Byte;
 use console::style;
 use dialoguer::{theme::ColorfulTheme, Select};
-use log::{debug, error, info, log_enabled, Level, LevelFilter};
+use log::{debug, error, info, log_enabled, warn, Level, LevelFilter};
 use process::CommandExt;
-use std::collections::HashSet;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::Write;
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::process::Command as ProcessCommand;
-use std::thread;
-use std::time::Duration;
-use storage::EncryptedDevice;
-use storage::{BlockDevice, Filesystem, FilesystemType, LoopDevice, MountStack};
+use storage::{create_subvolumes, BlockDevice, Filesystem, FilesystemType, Layout, LoopDevice};
+use storage::{EncryptedDevice, KeySource, LuksKey, PartitionTableType};
+use storage::{MountStack, PartitionType, VolumeGroup, VolumeSize};
 use structopt::StructOpt;
 use tempfile::tempdir;
-use tool::Tool;
+use tool::{MountEntry, Tool};
 
 fn main() -> anyhow::Result<()> {
     // Get struct of args using structopt
@@ -47,12 +55,19 @@ fn main() -> anyhow::Result<()> {
         Command::Create(command) => create(command),
         Command::Chroot(command) => tool::chroot(command),
         Command::Qemu(command) => tool::qemu(command),
+        Command::Luks(command) => tool::luks(command),
+        Command::Shrink(command) => tool::shrink(command),
+        Command::Flash(command) => tool::flash(command),
+        Command::Sign(command) => tool::sign(command),
+        Command::Update(command) => tool::update(command),
     }?;
 
     Ok(())
 }
 
 /// Remove swap entry from fstab and any commented lines
+/// Also removes the subvolid option from btrfs entries, so a subvolume can be replaced by a
+/// snapshot and still be mounted by its name
 /// Returns an owned String
 ///
 /// # Arguments
@@ -61,10 +76,355 @@ fn fix_fstab(fstab: &str) -> String {
     fstab
         .lines()
         .filter(|line| !line.contains("swap") && !line.starts_with('#'))
-        .collect::<Vec<&str>>()
+        .map(|line| {
+            line.split_whitespace()
+                .enumerate()
+                .map(|(i, field)| {
+                    if i == 3 {
+                        field
+                            .split(',')
+                            .filter(|option| !option.starts_with("subvolid="))
+                            .collect::<Vec<&str>>()
+                            .join(",")
+                    } else {
+                        String::from(field)
+                    }
+                })
+                .collect::<Vec<String>>()
+                .join("\t")
+        })
+        .collect::<Vec<String>>()
         .join("\n")
 }
 
+/// Installs GRUB for BIOS (when the layout allows it) and UEFI, with shim for Secure Boot
+#[allow(clippy::too_many_arguments)]
+fn install_grub(
+    arch_chroot: &Tool,
+    mount_point: &Path,
+    layout: &Layout,
+    disk_path: &Path,
+    partition_table: PartitionTableType,
+    ab_slots: bool,
+    encrypted: bool,
+    kernel_parameters: &KernelParameters,
+    default_kernel: &str,
+) -> anyhow::Result<()> {
+    let defaults_path = mount_point.join("etc/default/grub");
+    let mut defaults =
+        fs::read_to_string(&defaults_path).context("Failed to read /etc/default/grub")?;
+
+    if encrypted && layout.boot_on_root() {
+        debug!("/boot is encrypted. Letting GRUB unlock the root partition");
+        defaults = bootloader::set_grub_default(&defaults, "GRUB_ENABLE_CRYPTODISK", "y");
+    }
+
+    // Merges with the command line a preset script may have set, rather than appending a second
+    // assignment which would override it
+    let mut cmdline = KernelParameters::parse(
+        &bootloader::grub_default(&defaults, "GRUB_CMDLINE_LINUX").unwrap_or_default(),
+    );
+    cmdline.merge(kernel_parameters);
+    debug!("Kernel command line: {}", cmdline);
+    defaults = bootloader::set_grub_default(&defaults, "GRUB_CMDLINE_LINUX", &cmdline.to_string());
+
+    // grub-mkconfig puts the kernel with the highest version first otherwise
+    defaults = bootloader::set_grub_default(
+        &defaults,
+        "GRUB_TOP_LEVEL",
+        &format!("/boot/vmlinuz-{}", default_kernel),
+    );
+    fs::write(&defaults_path, defaults).context("Failed to write to /etc/default/grub")?;
+
+    let esp_path = layout.esp_mount_point();
+    // With A/B slots GRUB lives on the EFI system partition, and each slot only has its own
+    // configuration
+    let grub_directory = if ab_slots {
+        esp_path
+    } else {
+        Path::new("/boot")
+    };
+    let mut grub_install = String::new();
+    // GRUB embeds its core image in the BIOS boot partition on GPT disks and in the gap after
+    // the MBR on msdos disks
+    if layout.has_partition_type(PartitionType::BiosBoot)
+        || partition_table == PartitionTableType::Msdos
+    {
+        grub_install.push_str(&format!(
+            "grub-install --target=i386-pc --boot-directory {} {} && ",
+            grub_directory.display(),
+            disk_path.display()
+        ));
+    } else {
+        info!("The partition layout has no BIOS boot partition. Installing GRUB for UEFI only");
+    }
+    grub_install.push_str(&format!("grub-install --target=x86_64-efi --efi-directory {} --boot-directory {} --removable && mkdir -p /boot/grub && grub-mkconfig -o /boot/grub/grub.cfg", esp_path.display(), grub_directory.display()));
+
+    arch_chroot
+        .execute()
+        .arg(mount_point)
+        .args(["bash", "-c"])
+        .arg(grub_install)
+        .run()
+        .context("Failed to install grub")?;
+
+    let esp_dir = mount_point.join(esp_path.strip_prefix("/").unwrap_or(esp_path));
+    if ab_slots {
+        debug!("Writing the GRUB configuration which picks the A/B slot");
+        fs::write(esp_dir.join("grub/grub.cfg"), ab::GRUB_CONFIG)
+            .context("Failed to write the A/B GRUB configuration")?;
+        ab::GrubEnv::initial().write(&esp_dir.join(ab::GRUBENV_PATH))?;
+    }
+
+    let efi_boot = esp_dir.join("EFI/BOOT");
+    let bootloader = efi_boot.join("BOOTX64.efi");
+    fs::rename(&bootloader, efi_boot.join("grubx64.efi")).context("Cannot move out grub")?;
+    fs::copy(
+        mount_point.join("usr/share/shim-signed/mmx64.efi"),
+        efi_boot.join("mmx64.efi"),
+    )
+    .context("Failed copying mmx64")?;
+    fs::copy(
+        mount_point.join("usr/share/shim-signed/shimx64.efi"),
+        bootloader,
+    )
+    .context("Failed copying shim")?;
+
+    debug!(
+        "GRUB configuration: {}",
+        fs::read_to_string(mount_point.join("boot/grub/grub.cfg"))
+            .unwrap_or_else(|e| e.to_string())
+    );
+
+    Ok(())
+}
+
+/// Installs systemd-boot to the removable path of the EFI system partition mounted at /boot, with
+/// a pacman hook which keeps the loader entries in line with the installed kernels
+/// With `uki`, each kernel package builds a unified kernel image, which systemd-boot finds without
+/// entries
+/// The first of the kernels boots by default.
+fn install_systemd_boot(
+    arch_chroot: &Tool,
+    mount_point: &Path,
+    root_uuid: &str,
+    kernel_parameters: &KernelParameters,
+    uki: bool,
+    kernels: &[String],
+) -> anyhow::Result<()> {
+    // Keeps the parameters a preset script may have written
+    let mut extra = fs::read_to_string(mount_point.join(bootloader::CMDLINE_PATH))
+        .map(|cmdline| KernelParameters::parse(&cmdline))
+        .unwrap_or_default();
+    extra.merge(kernel_parameters);
+    let cmdline = bootloader::kernel_cmdline(root_uuid, &extra).to_string();
+
+    debug!("Kernel command line: {}", cmdline);
+    fs::create_dir_all(mount_point.join("etc/kernel"))
+        .and_then(|_| fs::write(mount_point.join(bootloader::CMDLINE_PATH), cmdline))
+        .context("Failed to write the kernel command line")?;
+
+    if uki {
+        // The image embeds the kernel command line, so it is built once the command line is known
+        info!("Building the unified kernel images");
+        fs::create_dir_all(mount_point.join("boot/EFI/Linux"))
+            .context("Failed to create the EFI/Linux directory")?;
+        for kernel in kernels {
+            fs::write(
+                mount_point
+                    .join("etc/mkinitcpio.d")
+                    .join(format!("{}.preset", kernel)),
+                bootloader::uki_preset(kernel),
+            )
+            .context("Failed to write the mkinitcpio preset")?;
+        }
+        arch_chroot
+            .execute()
+            .arg(mount_point)
+            .args(["mkinitcpio", "-P"])
+            .run()
+            .context("Failed to build the unified kernel images")?;
+        for kernel in kernels {
+            for initramfs in &[
+                format!("initramfs-{}.img", kernel),
+                format!("initramfs-{}-fallback.img", kernel),
+            ] {
+                let path = mount_point.join("boot").join(initramfs);
+                if path.exists() {
+                    fs::remove_file(&path)
+                        .with_context(|| format!("Failed to remove {}", path.display()))?;
+                }
+            }
+        }
+    } else {
+        let script_path = mount_point.join(bootloader::ENTRIES_SCRIPT_PATH);
+        fs::write(&script_path, bootloader::ENTRIES_SCRIPT)
+            .and_then(|_| fs::set_permissions(&script_path, fs::Permissions::from_mode(0o755)))
+            .context("Failed to write the loader entries script")?;
+        let hook_path = mount_point.join(bootloader::ENTRIES_HOOK_PATH);
+        fs::create_dir_all(hook_path.parent().expect("Hook path has no parent"))
+            .and_then(|_| fs::write(&hook_path, bootloader::ENTRIES_HOOK))
+            .context("Failed to write the loader entries pacman hook")?;
+    }
+
+    // Without EFI variables bootctl installs to the removable path EFI/BOOT/BOOTX64.EFI as well,
+    // and leaves the boot entries of the host alone
+    arch_chroot
+        .execute()
+        .arg(mount_point)
+        .args(["bootctl", "install", "--esp-path=/boot", "--no-variables"])
+        .run()
+        .context("Failed to install systemd-boot")?;
+    fs::write(
+        mount_point.join("boot/loader/loader.conf"),
+        bootloader::loader_conf(&bootloader::entry_name(&kernels[0], uki)),
+    )
+    .context("Failed to write loader.conf")?;
+    if !uki {
+        arch_chroot
+            .execute()
+            .arg(mount_point)
+            .arg(Path::new("/").join(bootloader::ENTRIES_SCRIPT_PATH))
+            .run()
+            .context("Failed to write the loader entries")?;
+    }
+    // Updates systemd-boot on the EFI system partition after systemd itself was updated
+    arch_chroot
+        .execute()
+        .arg(mount_point)
+        .args(["systemctl", "enable", "systemd-boot-update.service"])
+        .run()
+        .context("Failed to enable systemd-boot-update")?;
+
+    debug!(
+        "Loader entries: {:?}",
+        fs::read_dir(mount_point.join("boot/loader/entries")).map(|entries| entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name())
+            .collect::<Vec<_>>())
+    );
+
+    Ok(())
+}
+
+/// Signs the boot chain on the EFI system partition for Secure Boot
+/// With `hook_keys`, a pacman hook signs the boot files again after updates, using the keys at that
+/// path inside the installation.
+fn sign_boot_chain(
+    sbsign: &Tool,
+    sbverify: &Tool,
+    signing_key: &secure_boot::SigningKey,
+    mount_point: &Path,
+    hook_keys: Option<&Path>,
+) -> anyhow::Result<()> {
+    info!("Signing the boot chain for Secure Boot");
+    secure_boot::sign_esp(sbsign, sbverify, signing_key, &mount_point.join("boot"))?;
+
+    if let Some(keys) = hook_keys {
+        info!("Installing the pacman hook which signs updated boot files");
+        let script_path = mount_point.join(secure_boot::SIGN_SCRIPT_PATH);
+        fs::write(&script_path, secure_boot::sign_script(keys))
+            .and_then(|_| fs::set_permissions(&script_path, fs::Permissions::from_mode(0o755)))
+            .context("Failed to write the signing script")?;
+        let hook_path = mount_point.join(secure_boot::SIGN_HOOK_PATH);
+        fs::create_dir_all(hook_path.parent().expect("Hook path has no parent"))
+            .and_then(|_| fs::write(&hook_path, secure_boot::SIGN_HOOK))
+            .context("Failed to write the signing pacman hook")?;
+    }
+
+    Ok(())
+}
+
+/// Installs a script with the systemd service which runs it, and enables the service
+fn install_service(
+    arch_chroot: &Tool,
+    mount_point: &Path,
+    script_path: &str,
+    script: &str,
+    service_name: &str,
+    service: &str,
+) -> anyhow::Result<()> {
+    let script_path = mount_point.join(script_path);
+    fs::write(&script_path, script)
+        .and_then(|_| fs::set_permissions(&script_path, fs::Permissions::from_mode(0o755)))
+        .with_context(|| format!("Failed to write the script of {}", service_name))?;
+    fs::write(
+        mount_point.join("etc/systemd/system").join(service_name),
+        service,
+    )
+    .with_context(|| format!("Failed to write {}", service_name))?;
+    arch_chroot
+        .execute()
+        .arg(mount_point)
+        .args(["systemctl", "enable", service_name])
+        .run()
+        .with_context(|| format!("Failed to enable {}", service_name))?;
+
+    Ok(())
+}
+
+/// Configures snapper for the btrfs root filesystem and lets grub-btrfs add the snapshots to the
+/// boot menu
+fn configure_snapper(arch_chroot: &Tool, mount_point: &Path) -> anyhow::Result<()> {
+    info!("Configuring snapper");
+
+    // snapper create-config insists on creating /.snapshots as a nested subvolume, so the
+    // configuration is written by hand to keep the separate @snapshots subvolume
+    let configs = mount_point.join("etc/snapper/configs");
+    fs::create_dir_all(&configs).context("Failed to create the snapper configuration")?;
+    let template =
+        fs::read_to_string(mount_point.join("usr/share/snapper/config-templates/default"))
+            .context("Failed to read the snapper configuration template")?;
+    let mut missing: Vec<&(&str, &str)> = constants::SNAPPER_ROOT_CONFIG.iter().collect();
+    let mut config: Vec<String> = template
+        .lines()
+        .map(|line| {
+            match missing
+                .iter()
+                .position(|(key, _)| line.starts_with(&format!("{}=", key)))
+            {
+                Some(i) => {
+                    let (key, value) = missing.remove(i);
+                    format!("{}=\"{}\"", key, value)
+                }
+                None => String::from(line),
+            }
+        })
+        .collect();
+    config.extend(
+        missing
+            .into_iter()
+            .map(|(key, value)| format!("{}=\"{}\"", key, value)),
+    );
+    fs::write(configs.join("root"), config.join("\n") + "\n")
+        .context("Failed to write the snapper configuration")?;
+    fs::write(
+        mount_point.join("etc/conf.d/snapper"),
+        "SNAPPER_CONFIGS=\"root\"\n",
+    )
+    .context("Failed to write the snapper configuration")?;
+    fs::set_permissions(
+        mount_point.join(".snapshots"),
+        fs::Permissions::from_mode(0o750),
+    )
+    .context("Failed to set the permissions of /.snapshots")?;
+
+    arch_chroot
+        .execute()
+        .arg(mount_point)
+        .args([
+            "systemctl",
+            "enable",
+            "snapper-timeline.timer",
+            "snapper-cleanup.timer",
+            "grub-btrfsd.service",
+        ])
+        .run()
+        .context("Failed to enable the snapper services")?;
+
+    Ok(())
+}
+
 /// Creates a file at the path provided, and mounts it to a loop device
 fn create_image(path: &Path, size: Byte, overwrite: bool) -> anyhow::Result<LoopDevice> {
     {
@@ -111,27 +471,320 @@ fn select_block_device(allow_non_removable: bool) -> anyhow::Result<PathBuf> {
     Ok(PathBuf::from("/dev").join(&devices[selection].name))
 }
 
+/// Adds a generated recovery key to the second key slot of the encrypted root partition
+/// The key is saved to `path`, or printed if no path is given
+fn add_recovery_key(
+    cryptsetup: &Tool,
+    device: &dyn BlockDevice,
+    key: &LuksKey,
+    path: Option<&Path>,
+) -> anyhow::Result<()> {
+    info!("Adding a recovery key");
+    let recovery_key = storage::generate_recovery_key()?;
+
+    EncryptedDevice::add_key(
+        cryptsetup,
+        device,
+        key,
+        &LuksKey::Passphrase(recovery_key.clone().into_bytes()),
+        Some(1),
+    )?;
+
+    match path {
+        Some(path) => {
+            fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o400)
+                .open(path)
+                .and_then(|mut f| writeln!(f, "{}", recovery_key))
+                .with_context(|| {
+                    format!("Failed to save the recovery key to {}", path.display())
+                })?;
+            info!("Recovery key saved to {}", path.display());
+        }
+        None => println!(
+            "{}\n\n    {}\n",
+            style("Recovery key for the encrypted root partition. Keep it in a safe place:").bold(),
+            style(recovery_key).green().bold()
+        ),
+    }
+
+    Ok(())
+}
+
+/// The partition layout of the installation: the one from the presets, or the default layout
+/// extended by the A/B slots and data partition the arguments ask for
+fn partition_layout(
+    command: &args::CreateCommand,
+    preset_layout: Option<Layout>,
+) -> anyhow::Result<Layout> {
+    let mut layout = match preset_layout {
+        Some(_) if command.boot_size.is_some() => {
+            return Err(anyhow!(
+                "--boot-size cannot be used with a partition layout from a preset"
+            ))
+        }
+        Some(_) if command.ab => {
+            return Err(anyhow!(
+                "--ab cannot be used with a partition layout from a preset"
+            ))
+        }
+        Some(_) if command.data_size.is_some() => {
+            return Err(anyhow!(
+                "--data-size cannot be used with a partition layout from a preset. Add an exfat partition to the layout instead"
+            ))
+        }
+        Some(layout) => layout,
+        None => Layout::default_layout(
+            command.boot_size.unwrap_or(300),
+            command.root_filesystem.unwrap_or(FilesystemType::Ext4),
+            command.partition_table,
+        ),
+    };
+    if command.root_size.is_some() && !command.ab && command.data_size.is_none() {
+        return Err(anyhow!("--root-size is only used with --ab or --data-size"));
+    }
+    if command.ab {
+        if command.partition_table != PartitionTableType::Gpt {
+            return Err(anyhow!("A/B slots require a GPT partition table"));
+        }
+        layout = layout.with_ab_slots(command.root_size.expect("A/B slots without a size"))?;
+    }
+    if let Some(data_size) = command.data_size {
+        let size = match data_size {
+            args::DataSize::Megabytes(megabytes) => {
+                Some(Byte::from_bytes(u128::from(megabytes) * 1024 * 1024))
+            }
+            args::DataSize::Rest if command.root_size.is_none() => {
+                return Err(anyhow!(
+                    "The root partition needs a size with --root-size when the data partition takes the rest of the disk"
+                ))
+            }
+            args::DataSize::Rest => None,
+        };
+        layout = layout.with_data_partition(
+            size,
+            command.root_size,
+            command
+                .data_mount_point
+                .as_deref()
+                .unwrap_or_else(|| Path::new("/data")),
+        )?;
+    }
+    layout.check_partition_table(command.partition_table)?;
+
+    Ok(layout)
+}
+
+/// Checks that the arguments, presets and layout fit together before the device is touched
+fn validate(
+    command: &args::CreateCommand,
+    presets: &presets::PresetsCollection,
+    layout: &Layout,
+) -> anyhow::Result<()> {
+    if command.snapper
+        && !layout
+            .root()
+            .subvolumes()
+            .iter()
+            .any(|s| s.mount_point == Path::new("/.snapshots"))
+    {
+        return Err(anyhow!(
+            "Snapper requires a btrfs root filesystem with a subvolume mounted at /.snapshots"
+        ));
+    }
+
+    let root_filesystem = layout
+        .root()
+        .filesystem
+        .expect("Root partition has no filesystem");
+    if command.lvm {
+        if root_filesystem == FilesystemType::Btrfs {
+            return Err(anyhow!(
+                "LVM cannot be used with a btrfs root filesystem. Use subvolumes instead"
+            ));
+        }
+        if layout
+            .partitions()
+            .iter()
+            .any(|p| p.mount_point.as_deref() == Some(Path::new("/home")))
+        {
+            return Err(anyhow!(
+                "LVM cannot be used with a layout which already has a /home partition"
+            ));
+        }
+    }
+
+    if command.toram && !layout.root().subvolumes().is_empty() {
+        return Err(anyhow!(
+            "Copying to RAM only copies the root subvolume. Use a root filesystem without subvolumes"
+        ));
+    }
+
+    if command.grow_root {
+        if !layout.partitions().last().is_some_and(|p| p.is_root()) {
+            return Err(anyhow!(
+                "The root partition must be the last partition to grow it on first boot"
+            ));
+        }
+        if root_filesystem == FilesystemType::F2fs {
+            return Err(anyhow!(
+                "F2FS cannot be grown while mounted. Use ext4 or btrfs to grow the root partition"
+            ));
+        }
+    }
+
+    if command.bootloader == Bootloader::SystemdBoot {
+        if command.ab || command.snapper {
+            return Err(anyhow!(
+                "systemd-boot cannot be used with --ab or --snapper, which rely on GRUB"
+            ));
+        }
+        if layout.esp_mount_point() != Path::new("/boot") {
+            return Err(anyhow!(
+                "systemd-boot loads the kernel from the EFI system partition, which must be mounted at /boot"
+            ));
+        }
+    }
+    if command.uki {
+        if command.bootloader != Bootloader::SystemdBoot {
+            return Err(anyhow!(
+                "Unified kernel images require --bootloader systemd-boot"
+            ));
+        }
+        if command.toram {
+            return Err(anyhow!(
+                "--toram cannot be used with --uki, since the kernel command line is part of the image"
+            ));
+        }
+    }
+    if let Some(keys) = &command.secure_boot_hook_keys {
+        if !keys.is_absolute() {
+            return Err(anyhow!(
+                "--secure-boot-hook-keys must be an absolute path inside the installation"
+            ));
+        }
+    }
+
+    if command.encrypted_root {
+        command
+            .luks_options()
+            .or(presets.luks.clone())
+            .validate(layout.boot_on_root())?;
+    }
+
+    Ok(())
+}
+
+/// Checks that the kernels are packages in the repositories pacstrap installs from, so a misspelled
+/// kernel fails before the device is partitioned
+fn check_kernel_packages(
+    pacman: &Tool,
+    pacman_conf: &Path,
+    kernels: &[String],
+) -> anyhow::Result<()> {
+    pacman
+        .execute()
+        .arg("--config")
+        .arg(pacman_conf)
+        .arg("-Si")
+        .args(kernels)
+        .run_text_output()
+        .with_context(|| {
+            format!(
+                "Cannot find the kernel packages {} in the repositories",
+                kernels.join(", ")
+            )
+        })?;
+
+    Ok(())
+}
+
 /// Creates the installation
 #[allow(clippy::cognitive_complexity)] // TODO: Split steps into functions and remove this
 fn create(command: args::CreateCommand) -> anyhow::Result<()> {
-    let presets = presets::PresetsCollection::load(&command.presets)?;
+    let mut presets = presets::PresetsCollection::load(&command.presets)?;
+
+    let mut kernels = command.kernels.clone();
+    for kernel in &presets.kernels {
+        if !kernels.contains(kernel) {
+            kernels.push(kernel.clone());
+        }
+    }
+    if kernels.is_empty() {
+        kernels.push(String::from(constants::DEFAULT_KERNEL));
+    }
+    let mut kernel_parameters = presets.kernel_parameters.clone();
+    kernel_parameters.extend(command.kernel_parameters.iter().map(String::as_str));
+
+    let layout = partition_layout(&command, presets.layout.take())?;
+    validate(&command, &presets, &layout)?;
+
+    let root_filesystem = layout
+        .root()
+        .filesystem
+        .expect("Root partition has no filesystem");
+    let signing_key = match (&command.secure_boot_key, &command.secure_boot_cert) {
+        (Some(key), Some(certificate)) => Some(secure_boot::SigningKey::new(
+            key.clone(),
+            certificate.clone(),
+        )?),
+        _ => None,
+    };
+    let luks_options = command.luks_options().or(presets.luks);
+    let pacman_conf_path = command
+        .pacman_conf
+        .clone()
+        .unwrap_or_else(|| "/etc/pacman.conf".into());
 
-    let sgdisk = Tool::find("sgdisk")?;
+    let pacman = Tool::find("pacman")?;
     let pacstrap = Tool::find("pacstrap")?;
     let arch_chroot = Tool::find("arch-chroot")?;
     let genfstab = Tool::find("genfstab")?;
-    let mkfat = Tool::find("mkfs.fat")?;
-    let mkext4 = Tool::find("mkfs.ext4")?;
+    let mut mkfs = HashMap::new();
+    for fs_type in layout.partitions().iter().filter_map(|p| p.filesystem) {
+        if let Entry::Vacant(entry) = mkfs.entry(fs_type) {
+            entry.insert(Tool::find(fs_type.mkfs())?);
+        }
+    }
+    let btrfs = if mkfs.contains_key(&FilesystemType::Btrfs) {
+        Some(Tool::find("btrfs")?)
+    } else {
+        None
+    };
     let cryptsetup = if command.encrypted_root {
         Some(Tool::find("cryptsetup")?)
     } else {
         None
     };
-    let blkid = if command.encrypted_root {
+    let blkid = if command.encrypted_root || command.bootloader == Bootloader::SystemdBoot {
         Some(Tool::find("blkid")?)
     } else {
         None
     };
+    let lvm = if command.lvm {
+        Some(Tool::find("lvm")?)
+    } else {
+        None
+    };
+    let mkswap = if command.swap_size.is_some() {
+        Some(Tool::find("mkswap")?)
+    } else {
+        None
+    };
+    let signing_tools = if signing_key.is_some() {
+        Some((Tool::find("sbsign")?, Tool::find("sbverify")?))
+    } else {
+        None
+    };
+    let qemu_img = if command.image_format.is_some() {
+        Some(Tool::find("qemu-img")?)
+    } else {
+        None
+    };
+
+    check_kernel_packages(&pacman, &pacman_conf_path, &kernels)?;
 
     let storage_device_path = if let Some(path) = command.path {
         path
@@ -162,51 +815,158 @@ fn create(command: args::CreateCommand) -> anyhow::Result<()> {
     info!("Partitioning the block device");
     debug!("{:?}", disk_path);
 
-    let boot_size = command.boot_size.unwrap_or(300);
-
-    sgdisk
-        .execute()
-        .args(&[
-            "-Z",
-            "-o",
-            &format!("--new=1::+{}M", boot_size),
-            "--new=2::+1M",
-            "--largest-new=3",
-            "--typecode=1:EF00",
-            "--typecode=2:EF02",
-        ])
-        .arg(&disk_path)
-        .run()
+    let partition_table = layout.partition_table(
+        command.partition_table,
+        storage_device.sector_size()?,
+        storage_device.size()?,
+    )?;
+    storage_device
+        .write_partition_table(&partition_table)
         .context("Partitioning error")?;
 
-    thread::sleep(Duration::from_millis(1000));
-
-    info!("Formatting filesystems");
-    let boot_partition = storage_device.get_partition(constants::BOOT_PARTITION_INDEX)?;
-    let boot_filesystem = Filesystem::format(&boot_partition, FilesystemType::Vfat, &mkfat)?;
+    let partitions = storage_device
+        .wait_for_partitions(&partition_table.part_uuids(), constants::PARTITION_TIMEOUT)?;
 
-    let root_partition_base = storage_device.get_partition(constants::ROOT_PARTITION_INDEX)?;
+    let root_partition_base = &partitions[layout.root_index()];
     let encrypted_root = if let Some(cryptsetup) = &cryptsetup {
         info!("Encrypting the root filesystem");
-        EncryptedDevice::prepare(cryptsetup, &root_partition_base)?;
+        let key = command
+            .luks_key
+            .as_ref()
+            .unwrap_or(&KeySource::Prompt)
+            .resolve(command.image.map(|_| storage_device_path.as_path()))?;
+        EncryptedDevice::prepare(cryptsetup, root_partition_base, &key, &luks_options)?;
+
+        if let Some(recovery_key_path) = &command.recovery_key {
+            add_recovery_key(
+                cryptsetup,
+                root_partition_base,
+                &key,
+                recovery_key_path.as_deref(),
+            )?;
+        }
+
         Some(EncryptedDevice::open(
             cryptsetup,
-            &root_partition_base,
+            root_partition_base,
             "alma_root".into(),
+            &key,
         )?)
     } else {
         None
     };
 
-    let root_partition = if let Some(e) = encrypted_root.as_ref() {
-        e as &dyn BlockDevice
+    let volume_group = if let Some(lvm) = &lvm {
+        info!("Creating logical volumes");
+        Some(VolumeGroup::create(
+            lvm,
+            encrypted_root
+                .as_ref()
+                .expect("LVM requires an encrypted root"),
+            constants::LVM_VOLUME_GROUP,
+        )?)
     } else {
-        &root_partition_base as &dyn BlockDevice
+        None
+    };
+
+    // The home volume takes whatever is left, so it is created last
+    let mut logical_volumes = None;
+    if let Some(volume_group) = &volume_group {
+        let root = volume_group.create_volume(
+            "root",
+            command
+                .lvm_root_size
+                .map_or(VolumeSize::Percent(50), VolumeSize::Bytes),
+        )?;
+        let swap = command
+            .swap_size
+            .map(|size| volume_group.create_volume("swap", VolumeSize::Bytes(size)))
+            .transpose()?;
+        let home = volume_group.create_volume("home", VolumeSize::Free)?;
+        logical_volumes = Some((root, home, swap));
+    }
+
+    let root_block = match (&logical_volumes, &encrypted_root) {
+        (Some((root, _, _)), _) => root as &dyn BlockDevice,
+        (None, Some(e)) => e as &dyn BlockDevice,
+        (None, None) => root_partition_base as &dyn BlockDevice,
     };
 
-    let root_filesystem = Filesystem::format(root_partition, FilesystemType::Ext4, &mkext4)?;
+    info!("Formatting filesystems");
+    let mut filesystems = Vec::new();
+    for (spec, partition) in layout.partitions().iter().zip(&partitions) {
+        let fs_type = match spec.filesystem {
+            Some(fs_type) => fs_type,
+            None => continue,
+        };
+
+        let block = if spec.is_root() {
+            root_block
+        } else {
+            partition as &dyn BlockDevice
+        };
+        let filesystem =
+            Filesystem::format(block, fs_type, spec.label.as_deref(), &mkfs[&fs_type])?;
+
+        if let Some(default_subvolume) = spec.default_subvolume() {
+            create_subvolumes(
+                &filesystem,
+                btrfs.as_ref().expect("No tool for btrfs"),
+                &spec.subvolumes(),
+                &default_subvolume.name,
+            )?;
+        }
+
+        filesystems.push((spec, filesystem));
+    }
+
+    let home_filesystem = match &logical_volumes {
+        Some((_, home, swap)) => {
+            if let Some(swap) = swap {
+                mkswap
+                    .as_ref()
+                    .expect("mkswap not found")
+                    .execute()
+                    .args(["-L", "swap"])
+                    .arg(swap.path())
+                    .run()
+                    .context("Error creating the swap volume")?;
+            }
+            Some(Filesystem::format(
+                home,
+                root_filesystem,
+                None,
+                &mkfs[&root_filesystem],
+            )?)
+        }
+        None => None,
+    };
 
-    let mount_stack = tool::mount(mount_point.path(), &boot_filesystem, &root_filesystem)?;
+    let mut mount_entries = Vec::new();
+    for (spec, filesystem) in &filesystems {
+        let mount_point = match &spec.mount_point {
+            Some(mount_point) => mount_point,
+            None => continue,
+        };
+
+        let subvolumes = spec.subvolumes();
+        if subvolumes.is_empty() {
+            mount_entries.push(MountEntry::new(mount_point.clone(), filesystem));
+        }
+        for subvolume in subvolumes {
+            mount_entries.push(MountEntry {
+                mount_point: subvolume.mount_point,
+                filesystem,
+                subvolume: Some(subvolume.name),
+            });
+        }
+    }
+
+    if let Some(home_filesystem) = &home_filesystem {
+        mount_entries.push(MountEntry::new(PathBuf::from("/home"), home_filesystem));
+    }
+
+    let mount_stack = tool::mount(mount_point.path(), &mount_entries)?;
 
     if log_enabled!(Level::Debug) {
         debug!("lsblk:");
@@ -226,9 +986,40 @@ fn create(command: args::CreateCommand) -> anyhow::Result<()> {
         .collect();
 
     packages.extend(presets.packages);
+    packages.extend(kernels.iter().cloned());
+    packages.extend(
+        layout
+            .partitions()
+            .iter()
+            .filter_map(|p| p.filesystem)
+            .flat_map(|fs_type| fs_type.packages().iter().map(|s| String::from(*s))),
+    );
+    if command.lvm {
+        packages.insert(String::from("lvm2"));
+    }
+    if command.grow_root {
+        packages.extend(
+            constants::GROW_ROOT_PACKAGES
+                .iter()
+                .map(|s| String::from(*s)),
+        );
+    }
+    if command.bootloader != Bootloader::Grub {
+        packages.remove("grub");
+    }
+    if command.snapper {
+        packages.extend(constants::SNAPPER_PACKAGES.iter().map(|s| String::from(*s)));
+    }
+    if command.secure_boot_hook_keys.is_some() {
+        packages.extend(secure_boot::PACKAGES.iter().map(|s| String::from(*s)));
+    }
 
     let aur_pacakges = {
-        let mut p = vec![String::from("shim-signed")];
+        // shim is only set up in front of GRUB
+        let mut p = Vec::new();
+        if command.bootloader == Bootloader::Grub {
+            p.push(String::from("shim-signed"));
+        }
         p.extend(presets.aur_packages);
         p.extend(command.aur_packages);
         p
@@ -236,10 +1027,6 @@ fn create(command: args::CreateCommand) -> anyhow::Result<()> {
 
     packages.extend(constants::AUR_DEPENDENCIES.iter().map(|s| String::from(*s)));
 
-    let pacman_conf_path = command
-        .pacman_conf
-        .unwrap_or_else(|| "/etc/pacman.conf".into());
-
     info!("Bootstrapping system");
     pacstrap
         .execute()
@@ -252,11 +1039,25 @@ fn create(command: args::CreateCommand) -> anyhow::Result<()> {
         .run()
         .context("Pacstrap error")?;
 
+    for kernel in &kernels {
+        if !mount_point
+            .path()
+            .join("etc/mkinitcpio.d")
+            .join(format!("{}.preset", kernel))
+            .exists()
+        {
+            return Err(anyhow!(
+                "{} has no mkinitcpio preset. Is it a kernel package?",
+                kernel
+            ));
+        }
+    }
+
     // Copy pacman.conf to the image.
     fs::copy(pacman_conf_path, mount_point.path().join("etc/pacman.conf"))
         .context("Failed copying pacman.conf")?;
 
-    let fstab = fix_fstab(
+    let mut fstab = fix_fstab(
         &genfstab
             .execute()
             .arg("-U")
@@ -264,20 +1065,26 @@ fn create(command: args::CreateCommand) -> anyhow::Result<()> {
             .run_text_output()
             .context("fstab error")?,
     );
+    // genfstab only lists the swap of the host, which fix_fstab drops
+    if let Some((_, _, Some(swap))) = &logical_volumes {
+        fstab.push_str(&format!(
+            "\n{}\tnone\tswap\tdefaults\t0 0",
+            swap.path().display()
+        ));
+    }
     debug!("fstab:\n{}", fstab);
     fs::write(mount_point.path().join("etc/fstab"), fstab).context("fstab error")?;
 
     arch_chroot
         .execute()
         .arg(mount_point.path())
-        .args(&["passwd", "-d", "root"])
+        .args(["passwd", "-d", "root"])
         .run()
         .context("Failed to delete the root password")?;
 
     info!("Setting locale");
     fs::OpenOptions::new()
         .append(true)
-        .write(true)
         .open(mount_point.path().join("etc/locale.gen"))
         .and_then(|mut locale_gen| locale_gen.write_all(b"en_US.UTF-8 UTF-8\n"))
         .context("Failed to create locale.gen")?;
@@ -298,7 +1105,7 @@ fn create(command: args::CreateCommand) -> anyhow::Result<()> {
     arch_chroot
         .execute()
         .arg(mount_point.path())
-        .args(&["useradd", "-m", "aur"])
+        .args(["useradd", "-m", "aur"])
         .run()
         .context("Failed to create temporary user to install AUR packages")?;
 
@@ -309,7 +1116,7 @@ fn create(command: args::CreateCommand) -> anyhow::Result<()> {
     arch_chroot
         .execute()
         .arg(mount_point.path())
-        .args(&["sudo", "-u", "aur"])
+        .args(["sudo", "-u", "aur"])
         .arg("git")
         .arg("clone")
         .arg(format!(
@@ -323,7 +1130,7 @@ fn create(command: args::CreateCommand) -> anyhow::Result<()> {
     arch_chroot
         .execute()
         .arg(mount_point.path())
-        .args(&[
+        .args([
             "bash",
             "-c",
             &format!(
@@ -337,7 +1144,7 @@ fn create(command: args::CreateCommand) -> anyhow::Result<()> {
     arch_chroot
         .execute()
         .arg(mount_point.path())
-        .args(&["sudo", "-u", "aur"])
+        .args(["sudo", "-u", "aur"])
         .args(&command.aur_helper.install_command)
         .args(aur_pacakges)
         .run()
@@ -347,7 +1154,7 @@ fn create(command: args::CreateCommand) -> anyhow::Result<()> {
     arch_chroot
         .execute()
         .arg(mount_point.path())
-        .args(&["userdel", "-r", "aur"])
+        .args(["userdel", "-r", "aur"])
         .run()
         .context("Failed to delete temporary aur user")?;
 
@@ -413,10 +1220,47 @@ fn create(command: args::CreateCommand) -> anyhow::Result<()> {
     arch_chroot
         .execute()
         .arg(mount_point.path())
-        .args(&["systemctl", "enable", "NetworkManager"])
+        .args(["systemctl", "enable", "NetworkManager"])
         .run()
         .context("Failed to enable NetworkManager")?;
 
+    if command.snapper {
+        configure_snapper(&arch_chroot, mount_point.path())?;
+    }
+
+    if command.grow_root {
+        info!("Installing the first boot service which grows the root partition");
+        let script = grow_root::GrowRoot::new(
+            partition_table.part_uuids()[layout.root_index()].clone(),
+            encrypted_root.is_some(),
+            volume_group.is_some(),
+            root_filesystem,
+        )
+        .to_script()?;
+        install_service(
+            &arch_chroot,
+            mount_point.path(),
+            grow_root::SCRIPT_PATH,
+            &script,
+            grow_root::SERVICE_NAME,
+            grow_root::SERVICE,
+        )?;
+    }
+
+    if command.ab {
+        info!("Installing the service which confirms that slot A boots");
+        fs::write(mount_point.path().join(ab::SLOT_FILE), Slot::A.name())
+            .context("Failed to write the slot name")?;
+        install_service(
+            &arch_chroot,
+            mount_point.path(),
+            ab::SCRIPT_PATH,
+            ab::SCRIPT,
+            ab::SERVICE_NAME,
+            ab::SERVICE,
+        )?;
+    }
+
     info!("Configuring journald");
     fs::write(
         mount_point.path().join("etc/systemd/journald.conf"),
@@ -424,75 +1268,110 @@ fn create(command: args::CreateCommand) -> anyhow::Result<()> {
     )
     .context("Failed to write to journald.conf")?;
 
+    if command.overlay_root {
+        info!("Installing the root overlay hook");
+        initcpio::OVERLAY_HOOK.install(mount_point.path())?;
+    }
+
+    if command.toram {
+        info!("Installing the copy to RAM hook");
+        initcpio::TORAM_HOOK.install(mount_point.path())?;
+        // The systemd-boot entries script adds the entry itself
+        if command.bootloader == Bootloader::Grub {
+            let grub_script = mount_point.path().join("etc/grub.d/11_alma_toram");
+            fs::write(&grub_script, initcpio::TORAM_GRUB_SCRIPT)
+                .and_then(|_| fs::set_permissions(&grub_script, fs::Permissions::from_mode(0o755)))
+                .context("Failed to add the copy to RAM boot entry")?;
+        }
+    }
+
+    let luks_uuid = match &encrypted_root {
+        Some(_) => Some(storage::filesystem_uuid(
+            blkid.as_ref().expect("No tool for blkid"),
+            root_partition_base,
+        )?),
+        None => None,
+    };
+    debug!("Root partition UUID: {:?}", luks_uuid);
+
+    // The legacy encrypt hook finds the root partition on the kernel command line, while
+    // sd-encrypt reads the crypttab of the initramfs
+    let mut boot_parameters = KernelParameters::default();
+    if let Some(luks_uuid) = &luks_uuid {
+        if command.systemd_initramfs {
+            fs::write(
+                mount_point.path().join(initcpio::CRYPTTAB_PATH),
+                initcpio::crypttab(luks_uuid),
+            )
+            .context("Failed to write /etc/crypttab.initramfs")?;
+        } else {
+            boot_parameters.add(&bootloader::luks_parameter(luks_uuid));
+        }
+    }
+    boot_parameters.merge(&kernel_parameters);
+
+    if let Some(keymap) = &presets.initcpio.keymap {
+        let vconsole_path = mount_point.path().join(initcpio::VCONSOLE_PATH);
+        let vconsole = fs::read_to_string(&vconsole_path).unwrap_or_default();
+        fs::write(&vconsole_path, initcpio::set_keymap(&vconsole, keymap))
+            .context("Failed to write /etc/vconsole.conf")?;
+    }
+
     info!("Generating initramfs");
+    let features = initcpio::Features {
+        systemd: command.systemd_initramfs,
+        encrypted: encrypted_root.is_some(),
+        lvm: volume_group.is_some(),
+        snapshots: command.snapper,
+        overlay: command.overlay_root,
+        toram: command.toram,
+        microcode: command.uki,
+    };
     fs::write(
         mount_point.path().join("etc/mkinitcpio.conf"),
-        initcpio::Initcpio::new(encrypted_root.is_some()).to_config()?,
+        initcpio::Initcpio::new(features, root_filesystem, &presets.initcpio).to_config()?,
     )
     .context("Failed to write to mkinitcpio.conf")?;
     arch_chroot
         .execute()
         .arg(mount_point.path())
-        .args(&["mkinitcpio", "-p", "linux"])
+        .args(["mkinitcpio", "-P"])
         .run()
-        .context("Failed to run mkinitcpio - do you have the base and linux packages installed?")?;
-
-    if encrypted_root.is_some() {
-        debug!("Setting up GRUB for an encrypted root partition");
-
-        let uuid = blkid
-            .expect("No tool for blkid")
-            .execute()
-            .arg(root_partition_base.path())
-            .args(&["-o", "value", "-s", "UUID"])
-            .run_text_output()
-            .context("Failed to run blkid")?;
-        let trimmed = uuid.trim();
-        debug!("Root partition UUID: {}", trimmed);
-
-        let mut grub_file = fs::OpenOptions::new()
-            .append(true)
-            .open(mount_point.path().join("etc/default/grub"))
-            .context("Failed to create /etc/default/grub")?;
-
-        write!(
-            &mut grub_file,
-            "GRUB_CMDLINE_LINUX=\"cryptdevice=UUID={}:luks_root\"",
-            trimmed
-        )
-        .context("Failed to write to /etc/default/grub")?;
-    }
+        .context("Failed to run mkinitcpio")?;
 
     info!("Installing the Bootloader");
-    arch_chroot
-        .execute()
-        .arg(mount_point.path())
-        .args(&["bash", "-c"])
-        .arg(format!("grub-install --target=i386-pc --boot-directory /boot {} && grub-install --target=x86_64-efi --efi-directory /boot --boot-directory /boot --removable &&  grub-mkconfig -o /boot/grub/grub.cfg", disk_path.display()))
-        .run().context("Failed to install grub")?;
-
-    let bootloader = mount_point.path().join("boot/EFI/BOOT/BOOTX64.efi");
-    fs::rename(
-        &bootloader,
-        mount_point.path().join("boot/EFI/BOOT/grubx64.efi"),
-    )
-    .context("Cannot move out grub")?;
-    fs::copy(
-        mount_point.path().join("usr/share/shim-signed/mmx64.efi"),
-        mount_point.path().join("boot/EFI/BOOT/mmx64.efi"),
-    )
-    .context("Failed copying mmx64")?;
-    fs::copy(
-        mount_point.path().join("usr/share/shim-signed/shimx64.efi"),
-        bootloader,
-    )
-    .context("Failed copying shim")?;
+    match command.bootloader {
+        Bootloader::Grub => install_grub(
+            &arch_chroot,
+            mount_point.path(),
+            &layout,
+            disk_path,
+            command.partition_table,
+            command.ab,
+            encrypted_root.is_some(),
+            &boot_parameters,
+            &kernels[0],
+        )?,
+        Bootloader::SystemdBoot => install_systemd_boot(
+            &arch_chroot,
+            mount_point.path(),
+            &storage::filesystem_uuid(blkid.as_ref().expect("No tool for blkid"), root_block)?,
+            &boot_parameters,
+            command.uki,
+            &kernels,
+        )?,
+    }
 
-    debug!(
-        "GRUB configuration: {}",
-        fs::read_to_string(mount_point.path().join("boot/grub/grub.cfg"))
-            .unwrap_or_else(|e| e.to_string())
-    );
+    if let Some(signing_key) = &signing_key {
+        let (sbsign, sbverify) = signing_tools.as_ref().expect("No tools for signing");
+        sign_boot_chain(
+            sbsign,
+            sbverify,
+            signing_key,
+            mount_point.path(),
+            command.secure_boot_hook_keys.as_deref(),
+        )?;
+    }
 
     if command.interactive {
         info!("Dropping you to chroot. Do as you wish to customize the installation. Please exit by typing 'exit' instead of using Ctrl+D");
@@ -506,5 +1385,26 @@ fn create(command: args::CreateCommand) -> anyhow::Result<()> {
     info!("Unmounting filesystems");
     mount_stack.umount()?;
 
+    // Nothing may still be writing to the image while it is read
+    drop(volume_group);
+    drop(encrypted_root);
+    drop(image_loop);
+
+    if let Some(compression) = command.compress {
+        export::export(&storage_device_path, compression)?;
+    }
+
+    if let (Some(qemu_img), Some(format)) = (&qemu_img, command.image_format) {
+        export::convert(qemu_img, &storage_device_path, format)?;
+    }
+
+    if signing_key.is_some() && command.secure_boot_hook_keys.is_none() {
+        warn!(
+            "Kernel updates on the device rebuild the unified kernel images unsigned, and Secure \
+             Boot refuses to boot them. Run alma sign after updating, or install a signing hook \
+             with --secure-boot-hook-keys"
+        );
+    }
+
     Ok(())
 }
@@ -1,3 +1,6 @@
+use crate::bootloader::KernelParameters;
+use crate::initcpio::InitcpioOptions;
+use crate::storage::{Layout, LuksOptions, PartitionSpec};
 use anyhow::{anyhow, Context};
 use serde::Deserialize;
 use std::collections::HashSet;
@@ -13,6 +16,11 @@ struct Preset {
     environment_variables: Option<Vec<String>>,
     shared_directories: Option<Vec<PathBuf>>,
     aur_packages: Option<Vec<String>>,
+    kernels: Option<Vec<String>>,
+    kernel_parameters: Option<Vec<String>>,
+    partitions: Option<Vec<PartitionSpec>>,
+    luks: Option<LuksOptions>,
+    initcpio: Option<InitcpioOptions>,
 }
 
 fn visit_dirs(dir: &Path, filevec: &mut Vec<PathBuf>) -> Result<(), io::Error> {
@@ -38,18 +46,52 @@ impl Preset {
 
     fn process(
         &self,
-        packages: &mut HashSet<String>,
-        scripts: &mut Vec<Script>,
+        presets: &mut PresetsCollection,
         environment_variables: &mut HashSet<String>,
         path: &Path,
-        aur_packages: &mut HashSet<String>,
     ) -> anyhow::Result<()> {
         if let Some(preset_packages) = &self.packages {
-            packages.extend(preset_packages.clone());
+            presets.packages.extend(preset_packages.clone());
         }
 
         if let Some(preset_aur_packages) = &self.aur_packages {
-            aur_packages.extend(preset_aur_packages.clone());
+            presets.aur_packages.extend(preset_aur_packages.clone());
+        }
+
+        if let Some(preset_kernels) = &self.kernels {
+            for kernel in preset_kernels {
+                if !presets.kernels.contains(kernel) {
+                    presets.kernels.push(kernel.clone());
+                }
+            }
+        }
+
+        if let Some(kernel_parameters) = &self.kernel_parameters {
+            presets
+                .kernel_parameters
+                .extend(kernel_parameters.iter().map(String::as_str));
+        }
+
+        if let Some(preset_partitions) = &self.partitions {
+            if presets.layout.is_some() {
+                return Err(anyhow!(
+                    "Preset: {} - the partition layout is already defined by another preset",
+                    path.display()
+                ));
+            }
+            presets.layout = Some(
+                Layout::new(preset_partitions.clone())
+                    .with_context(|| format!("Preset: {}", path.display()))?,
+            );
+        }
+
+        // Later presets override the LUKS options of earlier ones
+        if let Some(preset_luks) = &self.luks {
+            presets.luks = preset_luks.clone().or(presets.luks.clone());
+        }
+
+        if let Some(preset_initcpio) = &self.initcpio {
+            presets.initcpio.merge(preset_initcpio);
         }
 
         if let Some(preset_environment_variables) = &self.environment_variables {
@@ -57,7 +99,7 @@ impl Preset {
         }
 
         if let Some(script_text) = &self.script {
-            scripts.push(Script {
+            presets.scripts.push(Script {
                 script_text: script_text.clone(),
                 shared_dirs: self
                     .shared_directories
@@ -66,9 +108,8 @@ impl Preset {
                         // Convert directories to absolute paths
                         // If any shared directory is not a directory then throw an error
                         x.iter()
-                            .cloned()
                             .map(|y| {
-                                let full_path = path.parent().expect("Path has no parent").join(&y);
+                                let full_path = path.parent().expect("Path has no parent").join(y);
                                 if full_path.is_dir() {
                                     Ok(full_path)
                                 } else {
@@ -96,14 +137,27 @@ pub struct Script {
 pub struct PresetsCollection {
     pub packages: HashSet<String>,
     pub aur_packages: HashSet<String>,
+    /// Kernel packages in the order the presets list them, the first one boots by default
+    pub kernels: Vec<String>,
+    pub kernel_parameters: KernelParameters,
     pub scripts: Vec<Script>,
+    pub layout: Option<Layout>,
+    pub luks: LuksOptions,
+    pub initcpio: InitcpioOptions,
 }
 
 impl PresetsCollection {
     pub fn load(list: &[PathBuf]) -> anyhow::Result<Self> {
-        let mut packages = HashSet::new();
-        let mut aur_packages = HashSet::new();
-        let mut scripts: Vec<Script> = Vec::new();
+        let mut presets = Self {
+            packages: HashSet::new(),
+            aur_packages: HashSet::new(),
+            kernels: Vec::new(),
+            kernel_parameters: KernelParameters::default(),
+            scripts: Vec::new(),
+            layout: None,
+            luks: LuksOptions::default(),
+            initcpio: InitcpioOptions::default(),
+        };
         let mut environment_variables = HashSet::new();
 
         for preset in list {
@@ -120,21 +174,13 @@ impl PresetsCollection {
 
                 for path in dir_paths {
                     Preset::load(&path)?.process(
-                        &mut packages,
-                        &mut scripts,
+                        &mut presets,
                         &mut environment_variables,
                         &path,
-                        &mut aur_packages,
                     )?;
                 }
             } else {
-                Preset::load(preset)?.process(
-                    &mut packages,
-                    &mut scripts,
-                    &mut environment_variables,
-                    preset,
-                    &mut aur_packages,
-                )?;
+                Preset::load(preset)?.process(&mut presets, &mut environment_variables, preset)?;
             }
         }
         let missing_envrionments: Vec<String> = environment_variables
@@ -149,10 +195,6 @@ impl PresetsCollection {
             ));
         }
 
-        Ok(Self {
-            packages,
-            aur_packages,
-            scripts,
-        })
+        Ok(presets)
     }
 }
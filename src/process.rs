@@ -1,11 +1,13 @@
 use anyhow::anyhow;
 use log::error;
-use std::process::Command;
+use std::io::Write;
+use std::process::{Command, Stdio};
 use std::str;
 
 pub trait CommandExt {
     fn run(&mut self) -> anyhow::Result<()>;
     fn run_text_output(&mut self) -> anyhow::Result<String>;
+    fn run_with_input(&mut self, input: &[u8]) -> anyhow::Result<()>;
 }
 
 impl CommandExt for Command {
@@ -32,4 +34,22 @@ impl CommandExt for Command {
             |_| anyhow!("Process output is not valid UTF-8"),
         )?))
     }
+
+    fn run_with_input(&mut self, input: &[u8]) -> anyhow::Result<()> {
+        let mut child = self.stdin(Stdio::piped()).spawn()?;
+
+        child
+            .stdin
+            .take()
+            .expect("Child process has no stdin")
+            .write_all(input)?;
+
+        let exit_status = child.wait()?;
+
+        if !exit_status.success() {
+            return Err(anyhow!("Bad exit code: {}", exit_status));
+        }
+
+        Ok(())
+    }
 }
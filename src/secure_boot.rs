@@ -0,0 +1,213 @@
+use crate::process::CommandExt;
+use crate::tool::Tool;
+use anyhow::{anyhow, Context};
+use log::{debug, info};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Directories of the EFI system partition holding the binaries the firmware verifies: the
+/// removable path, systemd-boot and the unified kernel images
+const SIGNED_DIRECTORIES: [&str; 3] = ["EFI/BOOT", "EFI/systemd", "EFI/Linux"];
+
+/// Paths relative to the root of the installation
+pub const SIGN_SCRIPT_PATH: &str = "usr/local/bin/alma-secure-boot-sign";
+pub const SIGN_HOOK_PATH: &str = "etc/pacman.d/hooks/95-alma-secure-boot.hook";
+/// Installed with --secure-boot-hook-keys, for the pacman hook
+pub const PACKAGES: [&str; 1] = ["sbsigntools"];
+
+/// Signs what mkinitcpio and systemd rebuilt, once the keys are in place
+/// The key and certificate paths and the binaries to sign are put in front by sign_script.
+static SIGN_SCRIPT: &str = r#"
+if [ ! -r "${key}" ] || [ ! -r "${cert}" ]; then
+    echo "warning: ${key} or ${cert} is missing. The boot files stay unsigned and Secure Boot refuses them" >&2
+    exit 0
+fi
+
+# systemd-boot-update installs the signed copy of systemd-boot when there is one
+for file in /usr/lib/systemd/boot/efi/systemd-boot*.efi; do
+    [ -e "${file}" ] || continue
+    if [ ! -e "${file}.signed" ] || [ "${file}" -nt "${file}.signed" ]; then
+        sbsign --key "${key}" --cert "${cert}" --output "${file}.signed" "${file}"
+    fi
+done
+
+for file in "${binaries[@]}"; do
+    [ -e "${file}" ] || continue
+    if ! sbverify --cert "${cert}" "${file}" >/dev/null 2>&1; then
+        sbsign --key "${key}" --cert "${cert}" --output "${file}.signed" "${file}"
+        mv "${file}.signed" "${file}"
+    fi
+done
+"#;
+
+/// Runs after the mkinitcpio hook, which rebuilds the unified kernel images
+pub static SIGN_HOOK: &str = "[Trigger]
+Type = Path
+Operation = Install
+Operation = Upgrade
+Target = usr/lib/modules/*/vmlinuz
+Target = usr/lib/initcpio/*
+Target = usr/lib/firmware/*
+Target = usr/lib/systemd/boot/efi/systemd-boot*.efi
+Target = boot/*-ucode.img
+
+[Action]
+Description = Signing the boot files for Secure Boot...
+When = PostTransaction
+Exec = /usr/local/bin/alma-secure-boot-sign
+Depends = sbsigntools
+";
+
+/// The script of the pacman hook which signs with db.key and db.crt from the given directory of
+/// the installation
+pub fn sign_script(keys: &Path) -> String {
+    let binaries: Vec<String> = SIGNED_DIRECTORIES
+        .iter()
+        .map(|directory| format!("/boot/{}/*.[Ee][Ff][Ii]", directory))
+        .collect();
+    format!(
+        "#!/bin/bash
+set -euo pipefail
+
+key=\"{0}/db.key\"
+cert=\"{0}/db.crt\"
+binaries=({1})
+{2}",
+        keys.display(),
+        binaries.join(" "),
+        SIGN_SCRIPT
+    )
+}
+
+/// A db key and its certificate in PEM format, which sign the boot chain
+pub struct SigningKey {
+    key: PathBuf,
+    certificate: PathBuf,
+}
+
+impl SigningKey {
+    pub fn new(key: PathBuf, certificate: PathBuf) -> anyhow::Result<Self> {
+        for path in &[&key, &certificate] {
+            fs::File::open(path)
+                .with_context(|| format!("Cannot read the Secure Boot key {}", path.display()))?;
+        }
+        Ok(Self { key, certificate })
+    }
+}
+
+/// The EFI binaries in the given EFI system partition
+fn efi_binaries(esp: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut binaries = Vec::new();
+    for directory in SIGNED_DIRECTORIES.iter().map(|d| esp.join(d)) {
+        if !directory.exists() {
+            continue;
+        }
+        for entry in fs::read_dir(&directory)
+            .with_context(|| format!("Error reading {}", directory.display()))?
+        {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("efi"))
+            {
+                binaries.push(path);
+            }
+        }
+    }
+    binaries.sort();
+    Ok(binaries)
+}
+
+/// Signs the bootloader and the unified kernel images in the given EFI system partition
+/// Binaries which the certificate already verifies are left alone, so this can run again after
+/// updates replaced some of them.
+pub fn sign_esp(
+    sbsign: &Tool,
+    sbverify: &Tool,
+    signing_key: &SigningKey,
+    esp: &Path,
+) -> anyhow::Result<()> {
+    let binaries = efi_binaries(esp)?;
+    if binaries.is_empty() {
+        return Err(anyhow!("There are no EFI binaries to sign"));
+    }
+
+    for binary in binaries {
+        let signed = sbverify
+            .execute()
+            .arg("--cert")
+            .arg(&signing_key.certificate)
+            .arg(&binary)
+            .output()
+            .context("Failed to run sbverify")?
+            .status
+            .success();
+        let name = binary.strip_prefix(esp).unwrap_or(&binary);
+        if signed {
+            debug!("{} is already signed", name.display());
+            continue;
+        }
+
+        info!("Signing {}", name.display());
+        let output = binary.with_extension("efi.signed");
+        sbsign
+            .execute()
+            .arg("--key")
+            .arg(&signing_key.key)
+            .arg("--cert")
+            .arg(&signing_key.certificate)
+            .arg("--output")
+            .arg(&output)
+            .arg(&binary)
+            .run()
+            .with_context(|| format!("Failed to sign {}", name.display()))?;
+        fs::rename(&output, &binary)
+            .with_context(|| format!("Failed to replace {}", name.display()))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_boot_chain() {
+        let esp = tempfile::tempdir().unwrap();
+        for file in &[
+            "EFI/BOOT/BOOTX64.EFI",
+            "EFI/systemd/systemd-bootx64.efi",
+            "EFI/Linux/arch-linux.efi",
+            "loader/loader.conf",
+            "vmlinuz-linux",
+        ] {
+            let path = esp.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+
+        let names: Vec<PathBuf> = efi_binaries(esp.path())
+            .unwrap()
+            .iter()
+            .map(|p| p.strip_prefix(esp.path()).unwrap().to_path_buf())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                PathBuf::from("EFI/BOOT/BOOTX64.EFI"),
+                PathBuf::from("EFI/Linux/arch-linux.efi"),
+                PathBuf::from("EFI/systemd/systemd-bootx64.efi"),
+            ]
+        );
+    }
+
+    #[test]
+    fn hook_script_signs_the_same_directories() {
+        let script = sign_script(Path::new("/etc/secureboot"));
+        assert!(script.contains("key=\"/etc/secureboot/db.key\"\n"));
+        for directory in &SIGNED_DIRECTORIES {
+            assert!(script.contains(&format!("/boot/{}/*.[Ee][Ff][Ii]", directory)));
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use super::gpt::format_guid;
+use super::layout::Subvolume;
+use super::markers::BlockDevice;
+use super::{Filesystem, MountStack};
+use crate::process::CommandExt;
+use crate::tool::Tool;
+use anyhow::Context;
+use log::debug;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use tempfile::tempdir;
+
+static BTRFS_MAGIC: &[u8] = b"_BHRfS_M";
+const SUPERBLOCK_OFFSET: u64 = 0x10000;
+
+/// Creates the given subvolumes in a freshly formatted btrfs filesystem
+/// The subvolume named `default_subvolume` becomes the default subvolume, which is what gets
+/// mounted when no subvol option is given
+pub fn create_subvolumes(
+    filesystem: &Filesystem,
+    btrfs: &Tool,
+    subvolumes: &[Subvolume],
+    default_subvolume: &str,
+) -> anyhow::Result<()> {
+    let mount_point = tempdir().context("Error creating a temporary directory")?;
+    let mut mount_stack = MountStack::new();
+    mount_stack
+        .mount(filesystem, mount_point.path().into(), None)
+        .context("Error mounting the btrfs filesystem")?;
+
+    for subvolume in subvolumes {
+        debug!("Creating subvolume {}", subvolume.name);
+        btrfs
+            .execute()
+            .args(["subvolume", "create"])
+            .arg(mount_point.path().join(&subvolume.name))
+            .run()
+            .with_context(|| format!("Error creating the {} subvolume", subvolume.name))?;
+    }
+
+    btrfs
+        .execute()
+        .args(["subvolume", "set-default"])
+        .arg(mount_point.path().join(default_subvolume))
+        .run()
+        .context("Error setting the default subvolume")?;
+
+    mount_stack.umount()
+}
+
+/// Reads the UUID of a btrfs filesystem from its superblock
+/// Returns None if the device does not contain a btrfs filesystem
+pub fn btrfs_uuid(device: &dyn BlockDevice) -> anyhow::Result<Option<String>> {
+    let mut f = fs::File::open(device.path()).context("Error reading the btrfs superblock")?;
+
+    let mut superblock = [0; 0x48];
+    f.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))
+        .and_then(|_| f.read_exact(&mut superblock))
+        .context("Error reading the btrfs superblock")?;
+
+    if &superblock[0x40..0x48] != BTRFS_MAGIC {
+        return Ok(None);
+    }
+
+    Ok(Some(format_guid(&superblock[0x20..0x30])))
+}
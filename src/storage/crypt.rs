@@ -1,16 +1,377 @@
 use super::markers::BlockDevice;
 use crate::process::CommandExt;
 use crate::tool::Tool;
-use anyhow::Context;
-use log::{debug, warn};
+use anyhow::{anyhow, Context};
+use log::{debug, info, warn};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::unistd::pipe2;
+use serde::Deserialize;
+use std::env;
 use std::fs;
-use std::io::Read;
+use std::io::{self, Read, Write};
 use std::marker::PhantomData;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::FromRawFd;
 use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
 
 static LUKS_MAGIC_1: &[u8] = &[0x4c, 0x55, 0x4b, 0x53, 0xba, 0xbe];
 static LUKS_MAGIC_2: &[u8] = &[0x53, 0x4b, 0x55, 0x4c, 0xba, 0xbe];
 
+const GENERATED_KEY_SIZE: usize = 64;
+
+/// Where the passphrase of an encrypted device comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySource {
+    /// cryptsetup asks for the passphrase in the terminal
+    Prompt,
+    /// The passphrase is the value of an environment variable
+    Env(String),
+    /// A key file, used as is
+    File(PathBuf),
+    /// The passphrase is read from the standard input
+    Stdin,
+    /// A random key file is generated. Without a path it is stored next to the image
+    Generate(Option<PathBuf>),
+}
+
+impl FromStr for KeySource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (kind, argument) = match s.find(':') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+
+        match (kind, argument) {
+            ("prompt", None) => Ok(KeySource::Prompt),
+            ("stdin", None) => Ok(KeySource::Stdin),
+            ("env", Some(var)) if !var.is_empty() => Ok(KeySource::Env(String::from(var))),
+            ("file", Some(path)) if !path.is_empty() => Ok(KeySource::File(PathBuf::from(path))),
+            ("generate", None) => Ok(KeySource::Generate(None)),
+            ("generate", Some(path)) if !path.is_empty() => {
+                Ok(KeySource::Generate(Some(PathBuf::from(path))))
+            }
+            _ => Err(anyhow!(
+                "Invalid key source: {}. Expected prompt, stdin, env:VAR, file:PATH or generate[:PATH]",
+                s
+            )),
+        }
+    }
+}
+
+impl KeySource {
+    /// Reads or generates the key
+    ///
+    /// `image_path` is the image being created, if any. Generated keys without an explicit path
+    /// are stored next to it.
+    pub fn resolve(&self, image_path: Option<&Path>) -> anyhow::Result<LuksKey> {
+        match self {
+            KeySource::Prompt => Ok(LuksKey::Prompt),
+            KeySource::Env(var) => env::var(var)
+                .map(|value| LuksKey::Passphrase(value.into_bytes()))
+                .with_context(|| format!("Cannot read the passphrase from ${}", var)),
+            KeySource::File(path) => {
+                if !path.is_file() {
+                    return Err(anyhow!("Key file {} does not exist", path.display()));
+                }
+                Ok(LuksKey::File(path.clone()))
+            }
+            KeySource::Stdin => {
+                let mut passphrase = Vec::new();
+                io::stdin()
+                    .read_to_end(&mut passphrase)
+                    .context("Cannot read the passphrase from the standard input")?;
+                // Match what cryptsetup does with a passphrase typed in the terminal
+                if passphrase.last() == Some(&b'\n') {
+                    passphrase.pop();
+                }
+                Ok(LuksKey::Passphrase(passphrase))
+            }
+            KeySource::Generate(path) => {
+                let path = match (path, image_path) {
+                    (Some(path), _) => path.clone(),
+                    (None, Some(image_path)) => {
+                        let mut key_path = image_path.as_os_str().to_owned();
+                        key_path.push(".key");
+                        PathBuf::from(key_path)
+                    }
+                    (None, None) => {
+                        return Err(anyhow!(
+                        "A path for the generated key file is required when not creating an image"
+                    ))
+                    }
+                };
+                generate_key_file(&path)?;
+                Ok(LuksKey::File(path))
+            }
+        }
+    }
+}
+
+fn generate_key_file(path: &Path) -> anyhow::Result<()> {
+    let mut key = [0; GENERATED_KEY_SIZE];
+    fs::File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut key))
+        .context("Error generating a random key")?;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o400)
+        .open(path)
+        .and_then(|mut f| f.write_all(&key))
+        .with_context(|| format!("Error writing the key file {}", path.display()))?;
+
+    info!("Generated key file {}", path.display());
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LuksType {
+    Luks1,
+    Luks2,
+}
+
+impl FromStr for LuksType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "luks1" => Ok(LuksType::Luks1),
+            "luks2" => Ok(LuksType::Luks2),
+            _ => Err(anyhow!("Unknown LUKS type: {}", s)),
+        }
+    }
+}
+
+impl LuksType {
+    fn as_str(self) -> &'static str {
+        match self {
+            LuksType::Luks1 => "luks1",
+            LuksType::Luks2 => "luks2",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pbkdf {
+    Pbkdf2,
+    Argon2i,
+    Argon2id,
+}
+
+impl FromStr for Pbkdf {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "pbkdf2" => Ok(Pbkdf::Pbkdf2),
+            "argon2i" => Ok(Pbkdf::Argon2i),
+            "argon2id" => Ok(Pbkdf::Argon2id),
+            _ => Err(anyhow!("Unknown PBKDF: {}", s)),
+        }
+    }
+}
+
+impl Pbkdf {
+    fn as_str(self) -> &'static str {
+        match self {
+            Pbkdf::Pbkdf2 => "pbkdf2",
+            Pbkdf::Argon2i => "argon2i",
+            Pbkdf::Argon2id => "argon2id",
+        }
+    }
+}
+
+/// Parameters for luksFormat. Anything left out uses the cryptsetup defaults
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LuksOptions {
+    #[serde(rename = "type")]
+    pub luks_type: Option<LuksType>,
+    pub cipher: Option<String>,
+    pub key_size: Option<u32>,
+    pub pbkdf: Option<Pbkdf>,
+    /// Milliseconds spent deriving the key
+    pub iter_time: Option<u32>,
+    /// Memory cost of argon2 in KiB
+    pub pbkdf_memory: Option<u32>,
+}
+
+impl LuksOptions {
+    /// Fills the options not set in self from other
+    pub fn or(self, other: LuksOptions) -> LuksOptions {
+        LuksOptions {
+            luks_type: self.luks_type.or(other.luks_type),
+            cipher: self.cipher.or(other.cipher),
+            key_size: self.key_size.or(other.key_size),
+            pbkdf: self.pbkdf.or(other.pbkdf),
+            iter_time: self.iter_time.or(other.iter_time),
+            pbkdf_memory: self.pbkdf_memory.or(other.pbkdf_memory),
+        }
+    }
+
+    /// The key derivation function cryptsetup ends up using
+    fn effective_pbkdf(&self) -> Pbkdf {
+        match (self.pbkdf, self.luks_type) {
+            (Some(pbkdf), _) => pbkdf,
+            (None, Some(LuksType::Luks1)) => Pbkdf::Pbkdf2,
+            (None, _) => Pbkdf::Argon2id,
+        }
+    }
+
+    /// Rejects combinations that cryptsetup refuses or that leave the device unbootable
+    ///
+    /// `grub_unlocks` is set when /boot lives on the encrypted partition, so GRUB has to unlock
+    /// it before loading the kernel.
+    pub fn validate(&self, grub_unlocks: bool) -> anyhow::Result<()> {
+        let pbkdf = self.effective_pbkdf();
+
+        if self.luks_type == Some(LuksType::Luks1) && pbkdf != Pbkdf::Pbkdf2 {
+            return Err(anyhow!(
+                "LUKS1 only supports the pbkdf2 key derivation function"
+            ));
+        }
+
+        if self.pbkdf_memory.is_some() && pbkdf == Pbkdf::Pbkdf2 {
+            return Err(anyhow!(
+                "The PBKDF memory cost only applies to argon2i and argon2id"
+            ));
+        }
+
+        if let Some(key_size) = self.key_size {
+            if key_size == 0 || key_size % 8 != 0 {
+                return Err(anyhow!("The key size must be a multiple of 8 bits"));
+            }
+        }
+
+        if grub_unlocks && pbkdf != Pbkdf::Pbkdf2 {
+            return Err(anyhow!(
+                "/boot is on the encrypted root partition and GRUB cannot unlock LUKS2 with {}. \
+                 Use --luks-pbkdf pbkdf2 or --luks-type luks1",
+                pbkdf.as_str()
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(luks_type) = self.luks_type {
+            args.push(format!("--type={}", luks_type.as_str()));
+        }
+        if let Some(cipher) = &self.cipher {
+            args.push(format!("--cipher={}", cipher));
+        }
+        if let Some(key_size) = self.key_size {
+            args.push(format!("--key-size={}", key_size));
+        }
+        if let Some(pbkdf) = self.pbkdf {
+            args.push(format!("--pbkdf={}", pbkdf.as_str()));
+        }
+        if let Some(iter_time) = self.iter_time {
+            args.push(format!("--iter-time={}", iter_time));
+        }
+        if let Some(pbkdf_memory) = self.pbkdf_memory {
+            args.push(format!("--pbkdf-memory={}", pbkdf_memory));
+        }
+        args
+    }
+}
+
+/// A resolved key for cryptsetup
+#[derive(Debug)]
+pub enum LuksKey {
+    Prompt,
+    Passphrase(Vec<u8>),
+    File(PathBuf),
+}
+
+impl LuksKey {
+    /// Runs a cryptsetup command which needs the key
+    fn run(&self, command: &mut Command) -> anyhow::Result<()> {
+        match self {
+            LuksKey::Prompt => command.run(),
+            LuksKey::Passphrase(passphrase) => command
+                .arg("--key-file")
+                .arg("-")
+                .run_with_input(passphrase),
+            LuksKey::File(path) => command.arg("--key-file").arg(path).run(),
+        }
+    }
+
+    /// Runs a cryptsetup command which needs the key and takes a new key as its last argument
+    /// A new passphrase is handed over through a pipe, so it never ends up in a file.
+    fn run_with_new_key(&self, command: &mut Command, new_key: &LuksKey) -> anyhow::Result<()> {
+        let mut new_key_pipe = None;
+        match new_key {
+            LuksKey::Prompt => (),
+            LuksKey::File(path) => {
+                command.arg(path);
+            }
+            LuksKey::Passphrase(passphrase) => {
+                let (read_fd, write_fd) =
+                    pipe2(OFlag::O_CLOEXEC).context("Error creating a pipe for the new key")?;
+                let (read_end, mut write_end) = unsafe {
+                    (
+                        fs::File::from_raw_fd(read_fd),
+                        fs::File::from_raw_fd(write_fd),
+                    )
+                };
+                // Only the read end is inherited by cryptsetup
+                fcntl(read_fd, FcntlArg::F_SETFD(FdFlag::empty()))
+                    .context("Error creating a pipe for the new key")?;
+                // A passphrase fits in the pipe buffer, so it is written before cryptsetup runs
+                write_end
+                    .write_all(passphrase)
+                    .context("Error writing the new key")?;
+                drop(write_end);
+                command.arg(format!("/dev/fd/{}", read_fd));
+                new_key_pipe = Some(read_end);
+            }
+        }
+
+        let result = self.run(command);
+        drop(new_key_pipe);
+        result
+    }
+}
+
+/// Generates a recovery key which is easy to type on any keyboard layout
+///
+/// The key is made of 8 groups of 8 characters from the modhex alphabet, which has the same
+/// letters in all common layouts, and holds 256 bits of entropy.
+pub fn generate_recovery_key() -> anyhow::Result<String> {
+    const ALPHABET: &[u8] = b"cbdefghijklnrtuv";
+
+    let mut random = [0; 32];
+    fs::File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut random))
+        .context("Error generating a recovery key")?;
+
+    let characters: Vec<char> = random
+        .iter()
+        .flat_map(|b| {
+            vec![
+                ALPHABET[usize::from(b >> 4)],
+                ALPHABET[usize::from(b & 0xf)],
+            ]
+        })
+        .map(char::from)
+        .collect();
+
+    Ok(characters
+        .chunks(8)
+        .map(|group| group.iter().collect::<String>())
+        .collect::<Vec<String>>()
+        .join("-"))
+}
+
 #[derive(Debug)]
 pub struct EncryptedDevice<'t, 'o> {
     cryptsetup: &'t Tool,
@@ -20,15 +381,22 @@ pub struct EncryptedDevice<'t, 'o> {
 }
 
 impl<'t, 'o> EncryptedDevice<'t, 'o> {
-    pub fn prepare(cryptsetup: &Tool, device: &dyn BlockDevice) -> anyhow::Result<()> {
+    pub fn prepare(
+        cryptsetup: &Tool,
+        device: &dyn BlockDevice,
+        key: &LuksKey,
+        options: &LuksOptions,
+    ) -> anyhow::Result<()> {
         debug!("Preparing encrypted device in {}", device.path().display());
-        cryptsetup
-            .execute()
-            .arg("luksFormat")
-            .arg("-q")
-            .arg(device.path())
-            .run()
-            .context("Error setting up an encrypted device")?;
+        key.run(
+            cryptsetup
+                .execute()
+                .arg("luksFormat")
+                .arg("-q")
+                .args(options.to_args())
+                .arg(device.path()),
+        )
+        .context("Error setting up an encrypted device")?;
 
         Ok(())
     }
@@ -37,19 +405,21 @@ impl<'t, 'o> EncryptedDevice<'t, 'o> {
         cryptsetup: &'t Tool,
         device: &'o dyn BlockDevice,
         name: String,
+        key: &LuksKey,
     ) -> anyhow::Result<EncryptedDevice<'t, 'o>> {
         debug!(
             "Opening encrypted device {} as {}",
             device.path().display(),
             name
         );
-        cryptsetup
-            .execute()
-            .arg("open")
-            .arg(device.path())
-            .arg(&name)
-            .run()
-            .context("Error opening the encrypted device")?;
+        key.run(
+            cryptsetup
+                .execute()
+                .arg("open")
+                .arg(device.path())
+                .arg(&name),
+        )
+        .context("Error opening the encrypted device")?;
 
         let path = PathBuf::from("/dev/mapper").join(&name);
         Ok(Self {
@@ -60,6 +430,68 @@ impl<'t, 'o> EncryptedDevice<'t, 'o> {
         })
     }
 
+    /// Adds a key to an encrypted device, optionally into a specific key slot
+    pub fn add_key(
+        cryptsetup: &Tool,
+        device: &dyn BlockDevice,
+        key: &LuksKey,
+        new_key: &LuksKey,
+        slot: Option<u8>,
+    ) -> anyhow::Result<()> {
+        debug!("Adding a key to {}", device.path().display());
+        let mut command = cryptsetup.execute();
+        command.arg("luksAddKey");
+        if let Some(slot) = slot {
+            command.arg(format!("--key-slot={}", slot));
+        }
+        command.arg(device.path());
+        key.run_with_new_key(&mut command, new_key)
+            .context("Error adding a key to the encrypted device")
+    }
+
+    /// Replaces the given key of an encrypted device with a new one
+    pub fn change_key(
+        cryptsetup: &Tool,
+        device: &dyn BlockDevice,
+        key: &LuksKey,
+        new_key: &LuksKey,
+    ) -> anyhow::Result<()> {
+        debug!("Changing a key of {}", device.path().display());
+        key.run_with_new_key(
+            cryptsetup.execute().arg("luksChangeKey").arg(device.path()),
+            new_key,
+        )
+        .context("Error changing the key of the encrypted device")
+    }
+
+    /// Removes the given key from an encrypted device
+    pub fn remove_key(
+        cryptsetup: &Tool,
+        device: &dyn BlockDevice,
+        key: &LuksKey,
+    ) -> anyhow::Result<()> {
+        debug!("Removing a key from {}", device.path().display());
+        key.run(cryptsetup.execute().arg("luksRemoveKey").arg(device.path()))
+            .context("Error removing the key from the encrypted device")
+    }
+
+    /// Resizes the opened container to the given number of 512 byte sectors
+    /// LUKS2 keeps the volume key in the kernel keyring, so the key is needed again.
+    pub fn resize(&self, key: &LuksKey, sectors: u64) -> anyhow::Result<()> {
+        debug!(
+            "Resizing encrypted device {} to {} sectors",
+            self.name, sectors
+        );
+        key.run(
+            self.cryptsetup
+                .execute()
+                .arg("resize")
+                .arg(format!("--size={}", sectors))
+                .arg(&self.name),
+        )
+        .context("Error resizing the encrypted device")
+    }
+
     fn _close(&mut self) -> anyhow::Result<()> {
         debug!("Closing encrypted device {}", self.name);
         self.cryptsetup
@@ -100,3 +532,88 @@ pub fn is_encrypted_device(device: &dyn BlockDevice) -> anyhow::Result<bool> {
 
     Ok(buffer == LUKS_MAGIC_1 || buffer == LUKS_MAGIC_2)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_key_source() {
+        assert_eq!("prompt".parse::<KeySource>().unwrap(), KeySource::Prompt);
+        assert_eq!("stdin".parse::<KeySource>().unwrap(), KeySource::Stdin);
+        assert_eq!(
+            "env:ALMA_PASSPHRASE".parse::<KeySource>().unwrap(),
+            KeySource::Env(String::from("ALMA_PASSPHRASE"))
+        );
+        assert_eq!(
+            "file:/root/alma.key".parse::<KeySource>().unwrap(),
+            KeySource::File(PathBuf::from("/root/alma.key"))
+        );
+        assert_eq!(
+            "generate".parse::<KeySource>().unwrap(),
+            KeySource::Generate(None)
+        );
+        assert_eq!(
+            "generate:alma.key".parse::<KeySource>().unwrap(),
+            KeySource::Generate(Some(PathBuf::from("alma.key")))
+        );
+        assert!("env:".parse::<KeySource>().is_err());
+        assert!("password".parse::<KeySource>().is_err());
+    }
+
+    #[test]
+    fn new_passphrase_through_a_pipe() {
+        let mut command = Command::new("sh");
+        command.args([
+            "-c",
+            "case \"$1\" in /dev/fd/*) ;; *) exit 1 ;; esac; test \"$(cat \"$1\")\" = secret",
+            "sh",
+        ]);
+        LuksKey::Prompt
+            .run_with_new_key(&mut command, &LuksKey::Passphrase(b"secret".to_vec()))
+            .unwrap();
+    }
+
+    #[test]
+    fn luks_options_validation() {
+        let defaults = LuksOptions::default();
+        assert!(defaults.validate(false).is_ok());
+        assert!(defaults.validate(true).is_err());
+
+        let pbkdf2 = LuksOptions {
+            pbkdf: Some(Pbkdf::Pbkdf2),
+            ..LuksOptions::default()
+        };
+        assert!(pbkdf2.validate(true).is_ok());
+
+        let luks1 = LuksOptions {
+            luks_type: Some(LuksType::Luks1),
+            ..LuksOptions::default()
+        };
+        assert!(luks1.validate(true).is_ok());
+
+        let luks1_argon2 = LuksOptions {
+            luks_type: Some(LuksType::Luks1),
+            pbkdf: Some(Pbkdf::Argon2id),
+            ..LuksOptions::default()
+        };
+        assert!(luks1_argon2.validate(false).is_err());
+
+        let pbkdf2_memory = LuksOptions {
+            pbkdf: Some(Pbkdf::Pbkdf2),
+            pbkdf_memory: Some(65536),
+            ..LuksOptions::default()
+        };
+        assert!(pbkdf2_memory.validate(false).is_err());
+    }
+
+    #[test]
+    fn recovery_key_format() {
+        let key = generate_recovery_key().unwrap();
+        let groups: Vec<&str> = key.split('-').collect();
+        assert_eq!(groups.len(), 8);
+        assert!(groups
+            .iter()
+            .all(|g| g.len() == 8 && g.chars().all(|c| "cbdefghijklnrtuv".contains(c))));
+    }
+}
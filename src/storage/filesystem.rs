@@ -1,11 +1,28 @@
 use super::markers::BlockDevice;
 use crate::{process::CommandExt, tool::Tool};
-use anyhow::Context;
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::str::FromStr;
 
-#[derive(Debug, Clone, Copy)]
+/// Location and value of the magic number identifying each filesystem in its superblock
+static SUPERBLOCK_MAGICS: &[(FilesystemType, u64, &[u8])] = &[
+    (FilesystemType::Ext4, 0x438, &[0x53, 0xef]),
+    (FilesystemType::F2fs, 0x400, &[0x10, 0x20, 0xf5, 0xf2]),
+    (FilesystemType::Btrfs, 0x10040, b"_BHRfS_M"),
+    (FilesystemType::Vfat, 0x52, b"FAT32   "),
+    (FilesystemType::Exfat, 0x03, b"EXFAT   "),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum FilesystemType {
     Ext4,
     Vfat,
+    Btrfs,
+    F2fs,
+    Exfat,
 }
 
 impl FilesystemType {
@@ -13,8 +30,107 @@ impl FilesystemType {
         match self {
             FilesystemType::Ext4 => "ext4",
             FilesystemType::Vfat => "vfat",
+            FilesystemType::Btrfs => "btrfs",
+            FilesystemType::F2fs => "f2fs",
+            FilesystemType::Exfat => "exfat",
+        }
+    }
+
+    /// Options every filesystem of this type is mounted with
+    pub fn mount_options(self) -> Option<&'static str> {
+        match self {
+            FilesystemType::Btrfs => Some("compress=zstd"),
+            FilesystemType::F2fs => {
+                Some("compress_algorithm=zstd,compress_chksum,compress_extension=*,lazytime")
+            }
+            FilesystemType::Ext4 | FilesystemType::Vfat | FilesystemType::Exfat => None,
         }
     }
+
+    /// Packages required in the installation to maintain the filesystem
+    pub fn packages(self) -> &'static [&'static str] {
+        match self {
+            FilesystemType::Ext4 => &["e2fsprogs"],
+            FilesystemType::Vfat => &["dosfstools"],
+            FilesystemType::Btrfs => &["btrfs-progs"],
+            FilesystemType::F2fs => &["f2fs-tools"],
+            FilesystemType::Exfat => &["exfatprogs"],
+        }
+    }
+
+    /// Kernel modules the initramfs needs to mount a root filesystem of this type
+    /// The filesystems hook adds the filesystem drivers, but not the checksum modules f2fs loads
+    /// at mount time
+    pub fn initcpio_modules(self) -> &'static [&'static str] {
+        match self {
+            FilesystemType::F2fs => &["crc32_generic", "crc32c_generic"],
+            FilesystemType::Ext4
+            | FilesystemType::Vfat
+            | FilesystemType::Btrfs
+            | FilesystemType::Exfat => &[],
+        }
+    }
+
+    /// Detects the filesystem in a block device by looking for the magic number in its superblock
+    pub fn detect(block: &dyn BlockDevice) -> anyhow::Result<Option<Self>> {
+        let mut f = fs::File::open(block.path()).with_context(|| {
+            format!(
+                "Error detecting the filesystem of {}",
+                block.path().display()
+            )
+        })?;
+
+        for (fs_type, offset, magic) in SUPERBLOCK_MAGICS {
+            let mut buffer = vec![0; magic.len()];
+            let found = f
+                .seek(SeekFrom::Start(*offset))
+                .and_then(|_| f.read_exact(&mut buffer))
+                .is_ok()
+                && buffer == *magic;
+            if found {
+                return Ok(Some(*fs_type));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Name of the tool used to create the filesystem
+    pub fn mkfs(self) -> &'static str {
+        match self {
+            FilesystemType::Ext4 => "mkfs.ext4",
+            FilesystemType::Vfat => "mkfs.fat",
+            FilesystemType::Btrfs => "mkfs.btrfs",
+            FilesystemType::F2fs => "mkfs.f2fs",
+            FilesystemType::Exfat => "mkfs.exfat",
+        }
+    }
+}
+
+impl FromStr for FilesystemType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "ext4" => Ok(FilesystemType::Ext4),
+            "vfat" => Ok(FilesystemType::Vfat),
+            "btrfs" => Ok(FilesystemType::Btrfs),
+            "f2fs" => Ok(FilesystemType::F2fs),
+            "exfat" => Ok(FilesystemType::Exfat),
+            _ => Err(anyhow!("Unknown filesystem type: {}", s)),
+        }
+    }
+}
+
+/// The UUID of the filesystem or LUKS container on a block device
+pub fn filesystem_uuid(blkid: &Tool, block: &dyn BlockDevice) -> anyhow::Result<String> {
+    let uuid = blkid
+        .execute()
+        .arg(block.path())
+        .args(["-o", "value", "-s", "UUID"])
+        .run_text_output()
+        .context("Failed to run blkid")?;
+    Ok(String::from(uuid.trim()))
 }
 
 #[derive(Debug)]
@@ -27,13 +143,44 @@ impl<'a> Filesystem<'a> {
     pub fn format(
         block: &'a dyn BlockDevice,
         fs_type: FilesystemType,
+        label: Option<&str>,
         mkfs: &Tool,
     ) -> anyhow::Result<Self> {
         let mut command = mkfs.execute();
         match fs_type {
-            FilesystemType::Ext4 => command.arg("-F").arg(block.path()),
-            FilesystemType::Vfat => command.arg("-F32").arg(block.path()),
+            FilesystemType::Ext4 => {
+                command.arg("-F");
+                if let Some(label) = label {
+                    command.arg("-L").arg(label);
+                }
+            }
+            FilesystemType::Vfat => {
+                command.arg("-F32");
+                if let Some(label) = label {
+                    command.arg("-n").arg(label);
+                }
+            }
+            FilesystemType::Btrfs => {
+                command.arg("-f");
+                if let Some(label) = label {
+                    command.arg("-L").arg(label);
+                }
+            }
+            FilesystemType::F2fs => {
+                command
+                    .arg("-f")
+                    .args(["-O", "extra_attr,inode_checksum,sb_checksum,compression"]);
+                if let Some(label) = label {
+                    command.arg("-l").arg(label);
+                }
+            }
+            FilesystemType::Exfat => {
+                if let Some(label) = label {
+                    command.arg("-L").arg(label);
+                }
+            }
         };
+        command.arg(block.path());
 
         command.run().context("Error formatting filesystem")?;
 
@@ -0,0 +1,503 @@
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::str::FromStr;
+
+static SIGNATURE: &[u8] = b"EFI PART";
+const REVISION: u32 = 0x0001_0000;
+const HEADER_SIZE: u32 = 92;
+const ENTRY_COUNT: u32 = 128;
+const ENTRY_SIZE: u32 = 128;
+const NAME_LENGTH: usize = 36;
+const PROTECTIVE_MBR_TYPE: u8 = 0xEE;
+/// The part of the MBR in front of the disk signature, which BIOS bootloaders use
+const BOOT_CODE_SIZE: usize = 440;
+
+/// Formats 16 bytes in the textual UUID form, without any byte swapping
+pub fn format_guid(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        hex[0..4].concat(),
+        hex[4..6].concat(),
+        hex[6..8].concat(),
+        hex[8..10].concat(),
+        hex[10..16].concat()
+    )
+}
+
+/// A GUID in its on-disk form, where the first three fields are little endian
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    /// Generates a random (version 4) GUID
+    pub fn random() -> anyhow::Result<Self> {
+        let mut bytes = [0; 16];
+        fs::File::open("/dev/urandom")
+            .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+            .context("Error generating a GUID")?;
+
+        // The version and variant live in the big endian fields of the textual form
+        bytes[7] = (bytes[7] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Ok(Self(bytes))
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == [0; 16]
+    }
+
+    /// The bytes in textual order
+    fn swapped(&self) -> [u8; 16] {
+        let mut bytes = self.0;
+        bytes[0..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+        bytes
+    }
+}
+
+impl FromStr for Guid {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex: String = s.chars().filter(|c| *c != '-').collect();
+        if hex.len() != 32 || s.len() != 36 {
+            return Err(anyhow!("Invalid GUID: {}", s));
+        }
+
+        let mut bytes = [0; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|_| anyhow!("Invalid GUID: {}", s))?;
+        }
+
+        // Swapping is its own inverse
+        Ok(Self(Guid(bytes).swapped()))
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&format_guid(&self.swapped()))
+    }
+}
+
+/// Partition attribute bits defined by the UEFI specification and the Discoverable Partitions
+/// Specification
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PartitionAttribute {
+    RequiredPartition,
+    NoBlockIoProtocol,
+    LegacyBiosBootable,
+    ReadOnly,
+    Hidden,
+    NoAutomount,
+}
+
+impl PartitionAttribute {
+    pub fn bit(self) -> u64 {
+        let position = match self {
+            PartitionAttribute::RequiredPartition => 0,
+            PartitionAttribute::NoBlockIoProtocol => 1,
+            PartitionAttribute::LegacyBiosBootable => 2,
+            PartitionAttribute::ReadOnly => 60,
+            PartitionAttribute::Hidden => 62,
+            PartitionAttribute::NoAutomount => 63,
+        };
+        1 << position
+    }
+}
+
+/// An entry of the GPT partition array
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptPartition {
+    /// Position in the partition array, starting from 1
+    pub number: u32,
+    pub partition_type: Guid,
+    pub guid: Guid,
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub attributes: u64,
+    pub name: String,
+}
+
+impl GptPartition {
+    fn to_bytes(&self) -> anyhow::Result<[u8; ENTRY_SIZE as usize]> {
+        let mut entry = [0; ENTRY_SIZE as usize];
+        entry[0..16].copy_from_slice(&self.partition_type.0);
+        entry[16..32].copy_from_slice(&self.guid.0);
+        entry[32..40].copy_from_slice(&self.first_lba.to_le_bytes());
+        entry[40..48].copy_from_slice(&self.last_lba.to_le_bytes());
+        entry[48..56].copy_from_slice(&self.attributes.to_le_bytes());
+
+        let name: Vec<u16> = self.name.encode_utf16().collect();
+        if name.len() > NAME_LENGTH {
+            return Err(anyhow!(
+                "Partition name {} is longer than {} characters",
+                self.name,
+                NAME_LENGTH
+            ));
+        }
+        for (i, c) in name.iter().enumerate() {
+            entry[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+
+        Ok(entry)
+    }
+
+    fn from_bytes(number: u32, entry: &[u8]) -> Option<Self> {
+        let partition_type = Guid(entry[0..16].try_into().unwrap());
+        if partition_type.is_nil() {
+            return None;
+        }
+
+        let name: Vec<u16> = entry[56..56 + NAME_LENGTH * 2]
+            .chunks(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0)
+            .collect();
+
+        Some(Self {
+            number,
+            partition_type,
+            guid: Guid(entry[16..32].try_into().unwrap()),
+            first_lba: read_u64(entry, 32),
+            last_lba: read_u64(entry, 40),
+            attributes: read_u64(entry, 48),
+            name: String::from_utf16_lossy(&name),
+        })
+    }
+}
+
+/// A GUID partition table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptTable {
+    pub disk_guid: Guid,
+    pub sector_size: u64,
+    pub partitions: Vec<GptPartition>,
+}
+
+impl GptTable {
+    pub fn new(sector_size: u64) -> anyhow::Result<Self> {
+        Ok(Self {
+            disk_guid: Guid::random()?,
+            sector_size,
+            partitions: Vec::new(),
+        })
+    }
+
+    pub fn entries_sectors(&self) -> u64 {
+        u64::from(ENTRY_COUNT * ENTRY_SIZE).div_ceil(self.sector_size)
+    }
+
+    /// The first sector after the primary header and partition array
+    pub fn first_usable_lba(&self) -> u64 {
+        2 + self.entries_sectors()
+    }
+
+    /// The last sector before the backup partition array and header
+    pub fn last_usable_lba(&self, disk_sectors: u64) -> u64 {
+        disk_sectors.saturating_sub(2 + self.entries_sectors())
+    }
+
+    /// Writes the protective MBR and both copies of the GPT to the device
+    /// Whatever partition table was there before is overwritten.
+    pub fn write<D: Write + Seek>(&self, device: &mut D) -> anyhow::Result<()> {
+        self.write_with_boot_code(device, &[0; BOOT_CODE_SIZE])
+    }
+
+    /// Rewrites both copies of the GPT of the device, e.g. after it was resized
+    /// The boot code in the protective MBR, where GRUB puts its boot image, is kept.
+    pub fn update<D: Read + Write + Seek>(&self, device: &mut D) -> anyhow::Result<()> {
+        let mut boot_code = [0; BOOT_CODE_SIZE];
+        device
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| device.read_exact(&mut boot_code))
+            .context("Error reading the partition table")?;
+        self.write_with_boot_code(device, &boot_code)
+    }
+
+    fn write_with_boot_code<D: Write + Seek>(
+        &self,
+        device: &mut D,
+        boot_code: &[u8; BOOT_CODE_SIZE],
+    ) -> anyhow::Result<()> {
+        let disk_sectors = device_sectors(device, self.sector_size)?;
+        let last_lba = disk_sectors - 1;
+        let last_usable_lba = self.last_usable_lba(disk_sectors);
+        if last_usable_lba <= self.first_usable_lba() {
+            return Err(anyhow!("The device is too small for a GPT"));
+        }
+
+        let mut entries = vec![0; (self.entries_sectors() * self.sector_size) as usize];
+        for partition in &self.partitions {
+            if partition.number == 0 || partition.number > ENTRY_COUNT {
+                return Err(anyhow!("Invalid partition number {}", partition.number));
+            }
+            if partition.first_lba < self.first_usable_lba()
+                || partition.last_lba > last_usable_lba
+                || partition.first_lba > partition.last_lba
+            {
+                return Err(anyhow!(
+                    "Partition {} is outside of the usable space of the device",
+                    partition.number
+                ));
+            }
+            let offset = ((partition.number - 1) * ENTRY_SIZE) as usize;
+            entries[offset..offset + ENTRY_SIZE as usize].copy_from_slice(&partition.to_bytes()?);
+        }
+        let entries_crc = crc32fast::hash(&entries[..(ENTRY_COUNT * ENTRY_SIZE) as usize]);
+
+        let backup_entries_lba = last_usable_lba + 1;
+        let primary = self.header(1, last_lba, 2, last_usable_lba, entries_crc);
+        let backup = self.header(
+            last_lba,
+            1,
+            backup_entries_lba,
+            last_usable_lba,
+            entries_crc,
+        );
+
+        let mut mbr = protective_mbr(disk_sectors);
+        mbr[..BOOT_CODE_SIZE].copy_from_slice(boot_code);
+        self.write_sector(device, 0, &mbr)?;
+        self.write_sector(device, 1, &primary)?;
+        self.write_sector(device, 2, &entries)?;
+        self.write_sector(device, backup_entries_lba, &entries)?;
+        self.write_sector(device, last_lba, &backup)?;
+        device
+            .flush()
+            .context("Error writing the partition table")?;
+
+        Ok(())
+    }
+
+    fn header(
+        &self,
+        current_lba: u64,
+        backup_lba: u64,
+        entries_lba: u64,
+        last_usable_lba: u64,
+        entries_crc: u32,
+    ) -> Vec<u8> {
+        let mut header = vec![0; self.sector_size as usize];
+        header[0..8].copy_from_slice(SIGNATURE);
+        header[8..12].copy_from_slice(&REVISION.to_le_bytes());
+        header[12..16].copy_from_slice(&HEADER_SIZE.to_le_bytes());
+        header[24..32].copy_from_slice(&current_lba.to_le_bytes());
+        header[32..40].copy_from_slice(&backup_lba.to_le_bytes());
+        header[40..48].copy_from_slice(&self.first_usable_lba().to_le_bytes());
+        header[48..56].copy_from_slice(&last_usable_lba.to_le_bytes());
+        header[56..72].copy_from_slice(&self.disk_guid.0);
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&ENTRY_COUNT.to_le_bytes());
+        header[84..88].copy_from_slice(&ENTRY_SIZE.to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+
+        let header_crc = crc32fast::hash(&header[..HEADER_SIZE as usize]);
+        header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+        header
+    }
+
+    fn write_sector<D: Write + Seek>(
+        &self,
+        device: &mut D,
+        lba: u64,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        device
+            .seek(SeekFrom::Start(lba * self.sector_size))
+            .and_then(|_| device.write_all(data))
+            .context("Error writing the partition table")
+    }
+
+    /// Reads the GPT of the device
+    /// The backup copy at the end of the device is used when the primary one is damaged.
+    pub fn read<D: Read + Seek>(device: &mut D, sector_size: u64) -> anyhow::Result<Self> {
+        let disk_sectors = device_sectors(device, sector_size)?;
+
+        read_table(device, sector_size, 1).or_else(|primary_error| {
+            read_table(device, sector_size, disk_sectors - 1)
+                .map_err(|_| primary_error.context("No valid GPT found on the device"))
+        })
+    }
+}
+
+fn read_table<D: Read + Seek>(
+    device: &mut D,
+    sector_size: u64,
+    header_lba: u64,
+) -> anyhow::Result<GptTable> {
+    let mut header = vec![0; sector_size as usize];
+    device
+        .seek(SeekFrom::Start(header_lba * sector_size))
+        .and_then(|_| device.read_exact(&mut header))
+        .context("Error reading the partition table")?;
+
+    if &header[0..8] != SIGNATURE {
+        return Err(anyhow!("Missing GPT signature at sector {}", header_lba));
+    }
+    let header_size = read_u32(&header, 12) as usize;
+    if header_size < HEADER_SIZE as usize || header_size > header.len() {
+        return Err(anyhow!("Invalid GPT header size {}", header_size));
+    }
+    let header_crc = read_u32(&header, 16);
+    header[16..20].copy_from_slice(&[0; 4]);
+    if crc32fast::hash(&header[..header_size]) != header_crc {
+        return Err(anyhow!(
+            "GPT header checksum mismatch at sector {}",
+            header_lba
+        ));
+    }
+
+    let entries_lba = read_u64(&header, 72);
+    let entry_count = read_u32(&header, 80);
+    let entry_size = read_u32(&header, 84);
+    if entry_size < ENTRY_SIZE || entry_count > 1024 {
+        return Err(anyhow!("Unsupported GPT partition array"));
+    }
+
+    let mut entries = vec![0; (entry_count * entry_size) as usize];
+    device
+        .seek(SeekFrom::Start(entries_lba * sector_size))
+        .and_then(|_| device.read_exact(&mut entries))
+        .context("Error reading the partition table")?;
+    if crc32fast::hash(&entries) != read_u32(&header, 88) {
+        return Err(anyhow!("GPT partition array checksum mismatch"));
+    }
+
+    Ok(GptTable {
+        disk_guid: Guid(header[56..72].try_into().unwrap()),
+        sector_size,
+        partitions: entries
+            .chunks(entry_size as usize)
+            .enumerate()
+            .filter_map(|(i, entry)| GptPartition::from_bytes(i as u32 + 1, entry))
+            .collect(),
+    })
+}
+
+/// An MBR with a single partition covering the disk, so tools which only understand MBR leave
+/// the disk alone
+fn protective_mbr(disk_sectors: u64) -> [u8; 512] {
+    let mut mbr = [0; 512];
+    let entry = &mut mbr[446..462];
+    entry[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+    entry[4] = PROTECTIVE_MBR_TYPE;
+    entry[5..8].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+    entry[8..12].copy_from_slice(&1u32.to_le_bytes());
+    let size = (disk_sectors - 1).min(u64::from(u32::MAX)) as u32;
+    entry[12..16].copy_from_slice(&size.to_le_bytes());
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+    mbr
+}
+
+fn device_sectors<D: Seek>(device: &mut D, sector_size: u64) -> anyhow::Result<u64> {
+    let size = device
+        .seek(SeekFrom::End(0))
+        .context("Error querying the size of the device")?;
+    Ok(size / sector_size)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn table() -> GptTable {
+        let mut table = GptTable::new(512).unwrap();
+        table.partitions = vec![
+            GptPartition {
+                number: 1,
+                partition_type: "C12A7328-F81F-11D2-BA4B-00A0C93EC93B".parse().unwrap(),
+                guid: Guid::random().unwrap(),
+                first_lba: 2048,
+                last_lba: 4095,
+                attributes: PartitionAttribute::LegacyBiosBootable.bit(),
+                name: String::from("boot"),
+            },
+            GptPartition {
+                number: 2,
+                partition_type: "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709".parse().unwrap(),
+                guid: Guid::random().unwrap(),
+                first_lba: 4096,
+                last_lba: 16350,
+                attributes: 0,
+                name: String::from("root"),
+            },
+        ];
+        table
+    }
+
+    #[test]
+    fn guid_encoding() {
+        let guid: Guid = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B".parse().unwrap();
+        assert_eq!(
+            guid.0,
+            [
+                0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E,
+                0xC9, 0x3B
+            ]
+        );
+        assert_eq!(guid.to_string(), "c12a7328-f81f-11d2-ba4b-00a0c93ec93b");
+        assert!("C12A7328F81F11D2BA4B00A0C93EC93B".parse::<Guid>().is_err());
+
+        let random = Guid::random().unwrap().to_string();
+        assert_eq!(&random[14..15], "4");
+        assert!("89ab".contains(&random[19..20]));
+    }
+
+    #[test]
+    fn write_and_read() {
+        let table = table();
+        let mut disk = Cursor::new(vec![0; 8 * 1024 * 1024]);
+        table.write(&mut disk).unwrap();
+
+        let disk = disk.into_inner();
+        assert_eq!(&disk[510..512], &[0x55, 0xAA]);
+        assert_eq!(disk[446 + 4], PROTECTIVE_MBR_TYPE);
+        assert_eq!(&disk[512..520], SIGNATURE);
+        assert_eq!(&disk[disk.len() - 512..disk.len() - 504], SIGNATURE);
+
+        let read = GptTable::read(&mut Cursor::new(disk), 512).unwrap();
+        assert_eq!(read, table);
+    }
+
+    #[test]
+    fn read_backup_when_primary_is_damaged() {
+        let table = table();
+        let mut disk = Cursor::new(vec![0; 8 * 1024 * 1024]);
+        table.write(&mut disk).unwrap();
+
+        let mut disk = disk.into_inner();
+        disk[512 + 40] ^= 0xFF;
+        let read = GptTable::read(&mut Cursor::new(disk), 512).unwrap();
+        assert_eq!(read, table);
+
+        assert!(GptTable::read(&mut Cursor::new(vec![0; 1024 * 1024]), 512).is_err());
+    }
+
+    #[test]
+    fn rejects_partitions_outside_usable_space() {
+        let mut table = table();
+        table.partitions[1].last_lba = 16351;
+        assert!(table
+            .write(&mut Cursor::new(vec![0; 8 * 1024 * 1024]))
+            .is_err());
+    }
+}
@@ -0,0 +1,800 @@
+use super::filesystem::FilesystemType;
+use super::gpt::{GptPartition, GptTable, Guid, PartitionAttribute};
+use super::mbr::{
+    MbrPartition, MbrTable, BASIC_DATA_PARTITION_TYPE, ESP_PARTITION_TYPE, LINUX_PARTITION_TYPE,
+};
+use super::partition::Partition;
+use super::partition_table::{PartitionTable, PartitionTableType};
+use super::storage_device::StorageDevice;
+use crate::ab::{self, Slot};
+use anyhow::anyhow;
+use byte_unit::Byte;
+use serde::{Deserialize, Deserializer};
+use std::path::{Path, PathBuf};
+
+/// Partitions start at multiples of 1MiB, like most partitioning tools do
+const PARTITION_ALIGNMENT: u64 = 1024 * 1024;
+/// Partition names are stored as UTF-16 in 72 bytes
+const MAX_NAME_LENGTH: usize = 36;
+const MAX_MBR_PARTITIONS: usize = 4;
+
+/// GPT partition types known to ALMA
+///
+/// Types which are part of the Discoverable Partitions Specification can be located again by
+/// `alma chroot` without knowing the layout the device was created with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PartitionType {
+    Esp,
+    BiosBoot,
+    LinuxRoot,
+    LinuxHome,
+    LinuxFilesystem,
+    /// Data partition which Windows and macOS mount, such as exFAT
+    BasicData,
+}
+
+impl PartitionType {
+    pub fn guid(self) -> Guid {
+        let guid = match self {
+            PartitionType::Esp => "C12A7328-F81F-11D2-BA4B-00A0C93EC93B",
+            PartitionType::BiosBoot => "21686148-6449-6E6F-744E-656564454649",
+            PartitionType::LinuxRoot => "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709",
+            PartitionType::LinuxHome => "933AC7E1-2EB4-4F13-B844-0E14E2AEF915",
+            PartitionType::LinuxFilesystem => "0FC63DAF-8483-4772-8E79-3D69D8477DE4",
+            PartitionType::BasicData => "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7",
+        };
+        guid.parse().expect("Invalid partition type GUID")
+    }
+
+    /// The MBR partition type. BIOS boot partitions are GPT only
+    pub fn mbr_type(self) -> Option<u8> {
+        match self {
+            PartitionType::Esp => Some(ESP_PARTITION_TYPE),
+            PartitionType::BiosBoot => None,
+            PartitionType::LinuxRoot
+            | PartitionType::LinuxHome
+            | PartitionType::LinuxFilesystem => Some(LINUX_PARTITION_TYPE),
+            PartitionType::BasicData => Some(BASIC_DATA_PARTITION_TYPE),
+        }
+    }
+
+    /// MBR has a single type for all Linux partitions, so the root partition cannot be told
+    /// apart from the others
+    pub fn from_mbr_type(mbr_type: u8) -> Option<Self> {
+        match mbr_type {
+            ESP_PARTITION_TYPE => Some(PartitionType::Esp),
+            LINUX_PARTITION_TYPE => Some(PartitionType::LinuxFilesystem),
+            BASIC_DATA_PARTITION_TYPE => Some(PartitionType::BasicData),
+            _ => None,
+        }
+    }
+
+    pub fn from_guid(guid: &Guid) -> Option<Self> {
+        [
+            PartitionType::Esp,
+            PartitionType::BiosBoot,
+            PartitionType::LinuxRoot,
+            PartitionType::LinuxHome,
+            PartitionType::LinuxFilesystem,
+            PartitionType::BasicData,
+        ]
+        .iter()
+        .copied()
+        .find(|t| t.guid() == *guid)
+    }
+
+    /// Where a partition of this type is mounted when the layout is not known
+    pub fn discoverable_mount_point(self) -> Option<&'static str> {
+        match self {
+            PartitionType::Esp => Some("/boot"),
+            PartitionType::LinuxRoot => Some("/"),
+            PartitionType::LinuxHome => Some("/home"),
+            PartitionType::BiosBoot | PartitionType::LinuxFilesystem | PartitionType::BasicData => {
+                None
+            }
+        }
+    }
+}
+
+fn deserialize_size<'de, D>(deserializer: D) -> Result<Option<Byte>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let size: Option<String> = Option::deserialize(deserializer)?;
+    size.map(|s| {
+        Byte::from_str(&s).map_err(|_| serde::de::Error::custom(format!("Invalid size: {}", s)))
+    })
+    .transpose()
+}
+
+/// A btrfs subvolume and where it is mounted in the installation
+#[derive(Debug, Clone, Deserialize)]
+pub struct Subvolume {
+    pub name: String,
+    pub mount_point: PathBuf,
+}
+
+impl Subvolume {
+    fn new(name: &str, mount_point: &str) -> Self {
+        Self {
+            name: String::from(name),
+            mount_point: PathBuf::from(mount_point),
+        }
+    }
+}
+
+/// The subvolumes created in a btrfs root filesystem when the layout does not list any
+fn default_subvolumes() -> Vec<Subvolume> {
+    vec![
+        Subvolume::new("@", "/"),
+        Subvolume::new("@home", "/home"),
+        Subvolume::new("@var_log", "/var/log"),
+        Subvolume::new("@snapshots", "/.snapshots"),
+    ]
+}
+
+/// A single partition in the layout
+///
+/// A partition without a size takes the rest of the disk.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PartitionSpec {
+    pub name: String,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub size: Option<Byte>,
+    #[serde(rename = "type")]
+    pub partition_type: PartitionType,
+    pub filesystem: Option<FilesystemType>,
+    pub label: Option<String>,
+    pub mount_point: Option<PathBuf>,
+    subvolumes: Option<Vec<Subvolume>>,
+    #[serde(default)]
+    pub attributes: Vec<PartitionAttribute>,
+}
+
+impl PartitionSpec {
+    fn new(
+        name: &str,
+        size: Option<Byte>,
+        partition_type: PartitionType,
+        filesystem: Option<FilesystemType>,
+        mount_point: Option<&str>,
+    ) -> Self {
+        Self {
+            name: String::from(name),
+            size,
+            partition_type,
+            filesystem,
+            label: None,
+            mount_point: mount_point.map(PathBuf::from),
+            subvolumes: None,
+            attributes: Vec::new(),
+        }
+    }
+
+    /// The btrfs subvolumes of this partition
+    /// A btrfs root partition without explicit subvolumes gets the default set
+    pub fn subvolumes(&self) -> Vec<Subvolume> {
+        match (&self.subvolumes, self.filesystem) {
+            (Some(subvolumes), _) => subvolumes.clone(),
+            (None, Some(FilesystemType::Btrfs)) if self.is_root() => default_subvolumes(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The subvolume mounted at the partition's own mount point
+    pub fn default_subvolume(&self) -> Option<Subvolume> {
+        self.subvolumes()
+            .into_iter()
+            .find(|s| Some(&s.mount_point) == self.mount_point.as_ref())
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.mount_point.as_deref() == Some(Path::new("/"))
+    }
+}
+
+/// Partition layout of the device
+#[derive(Debug, Clone)]
+pub struct Layout {
+    partitions: Vec<PartitionSpec>,
+}
+
+impl Layout {
+    /// The layout used when no preset defines one: an EFI system partition, a BIOS boot
+    /// partition for GRUB and a root partition which takes the rest of the disk
+    /// MBR disks have no BIOS boot partition, since GRUB is embedded right after the MBR.
+    pub fn default_layout(
+        boot_size_mb: u32,
+        root_filesystem: FilesystemType,
+        table_type: PartitionTableType,
+    ) -> Self {
+        let mut partitions = vec![PartitionSpec::new(
+            "boot",
+            Some(Byte::from_bytes(u128::from(boot_size_mb) * 1024 * 1024)),
+            PartitionType::Esp,
+            Some(FilesystemType::Vfat),
+            Some("/boot"),
+        )];
+        if table_type == PartitionTableType::Gpt {
+            partitions.push(PartitionSpec::new(
+                "bios",
+                Some(Byte::from_bytes(1024 * 1024)),
+                PartitionType::BiosBoot,
+                None,
+                None,
+            ));
+        }
+        partitions.push(PartitionSpec::new(
+            "root",
+            None,
+            PartitionType::LinuxRoot,
+            Some(root_filesystem),
+            Some("/"),
+        ));
+
+        Self { partitions }
+    }
+
+    pub fn new(partitions: Vec<PartitionSpec>) -> anyhow::Result<Self> {
+        let roots = partitions.iter().filter(|p| p.is_root()).count();
+        if roots != 1 {
+            return Err(anyhow!(
+                "The partition layout must contain exactly one partition mounted at /, found {}",
+                roots
+            ));
+        }
+
+        let unsized_partitions: Vec<&str> = partitions
+            .iter()
+            .filter(|p| p.size.is_none())
+            .map(|p| p.name.as_str())
+            .collect();
+        if unsized_partitions.len() > 1 {
+            return Err(anyhow!(
+                "Partitions {} have no size. Only one partition may take the rest of the disk",
+                unsized_partitions.join(", ")
+            ));
+        }
+
+        if let Some(p) = partitions
+            .iter()
+            .find(|p| p.name.encode_utf16().count() > MAX_NAME_LENGTH)
+        {
+            return Err(anyhow!(
+                "Partition name {} is longer than {} characters",
+                p.name,
+                MAX_NAME_LENGTH
+            ));
+        }
+
+        if let Some(p) = partitions
+            .iter()
+            .find(|p| p.mount_point.is_some() && p.filesystem.is_none())
+        {
+            return Err(anyhow!(
+                "Partition {} has a mount point but no filesystem",
+                p.name
+            ));
+        }
+
+        for p in &partitions {
+            if p.subvolumes.is_some() && p.filesystem != Some(FilesystemType::Btrfs) {
+                return Err(anyhow!(
+                    "Partition {} has subvolumes but is not a btrfs filesystem",
+                    p.name
+                ));
+            }
+
+            if !p.subvolumes().is_empty() && p.default_subvolume().is_none() {
+                return Err(anyhow!(
+                    "Partition {} has no subvolume mounted at its mount point",
+                    p.name
+                ));
+            }
+        }
+
+        match partitions
+            .iter()
+            .find(|p| p.partition_type == PartitionType::Esp)
+        {
+            Some(esp) if esp.mount_point.is_some() => (),
+            _ => {
+                return Err(anyhow!(
+                    "The partition layout must contain a mounted EFI system partition"
+                ))
+            }
+        }
+
+        Ok(Self { partitions })
+    }
+
+    pub fn partitions(&self) -> &[PartitionSpec] {
+        &self.partitions
+    }
+
+    /// The index of the root partition in the layout (not the partition number)
+    pub fn root_index(&self) -> usize {
+        self.partitions
+            .iter()
+            .position(PartitionSpec::is_root)
+            .expect("Layout has no root partition")
+    }
+
+    /// The mount point of the EFI system partition inside the installation
+    pub fn esp_mount_point(&self) -> &Path {
+        self.partitions
+            .iter()
+            .find(|p| p.partition_type == PartitionType::Esp)
+            .and_then(|p| p.mount_point.as_deref())
+            .expect("Layout has no EFI system partition")
+    }
+
+    pub fn root(&self) -> &PartitionSpec {
+        &self.partitions[self.root_index()]
+    }
+
+    /// Whether /boot is part of the root filesystem instead of a partition of its own
+    pub fn boot_on_root(&self) -> bool {
+        !self
+            .partitions
+            .iter()
+            .any(|p| !p.is_root() && p.mount_point.as_deref() == Some(Path::new("/boot")))
+    }
+
+    pub fn has_partition_type(&self, partition_type: PartitionType) -> bool {
+        self.partitions
+            .iter()
+            .any(|p| p.partition_type == partition_type)
+    }
+
+    /// Checks that the layout can be stored in the given kind of partition table
+    pub fn check_partition_table(&self, table_type: PartitionTableType) -> anyhow::Result<()> {
+        if table_type == PartitionTableType::Gpt {
+            return Ok(());
+        }
+
+        if self.has_partition_type(PartitionType::BiosBoot) {
+            return Err(anyhow!(
+                "BIOS boot partitions can only be used with GPT. On MBR disks GRUB is embedded \
+                 right after the MBR"
+            ));
+        }
+
+        if self.partitions.len() > MAX_MBR_PARTITIONS {
+            return Err(anyhow!(
+                "An MBR partition table holds at most {} partitions, the layout has {}",
+                MAX_MBR_PARTITIONS,
+                self.partitions.len()
+            ));
+        }
+
+        // alma chroot takes the first Linux partition of an MBR disk as the root partition
+        let first_linux = self
+            .partitions
+            .iter()
+            .position(|p| p.partition_type.mbr_type() == Some(LINUX_PARTITION_TYPE));
+        if first_linux != Some(self.root_index()) {
+            return Err(anyhow!(
+                "With an MBR partition table the root partition must come before the other Linux \
+                 partitions"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Adds a partition in front of the others which Windows and macOS can read. Without a size it
+    /// takes the rest of the disk, and the root partition gets the given size instead
+    pub fn with_data_partition(
+        self,
+        size: Option<Byte>,
+        root_size: Option<Byte>,
+        mount_point: &Path,
+    ) -> anyhow::Result<Self> {
+        let mut data = PartitionSpec::new(
+            "data",
+            size,
+            PartitionType::BasicData,
+            Some(FilesystemType::Exfat),
+            None,
+        );
+        data.label = Some(String::from("ALMA DATA"));
+        data.mount_point = Some(mount_point.to_path_buf());
+
+        let mut partitions = vec![data];
+        partitions.extend(self.partitions.into_iter().map(|mut p| {
+            if p.is_root() && root_size.is_some() {
+                p.size = root_size;
+            }
+            p
+        }));
+        Self::new(partitions)
+    }
+
+    /// Turns the root partition into slot A of an A/B layout and adds slot B of the same size
+    /// right after it. /boot moves into the slots, so each one has its own kernel, and the EFI
+    /// system partition is mounted at /efi instead
+    pub fn with_ab_slots(self, slot_size: Byte) -> anyhow::Result<Self> {
+        let mut partitions = Vec::new();
+        for mut p in self.partitions {
+            if p.partition_type == PartitionType::Esp {
+                p.mount_point = Some(PathBuf::from(ab::ESP_MOUNT_POINT));
+            }
+            if !p.is_root() {
+                partitions.push(p);
+                continue;
+            }
+
+            if p.partition_type != PartitionType::LinuxRoot {
+                return Err(anyhow!("A/B slots need a partition of type linux-root"));
+            }
+            p.name = String::from("root_a");
+            p.size = Some(slot_size);
+            p.label = Some(String::from(Slot::A.label()));
+            // The slots are copied file by file, which does not cover other subvolumes
+            p.subvolumes = Some(Vec::new());
+
+            let mut slot_b = p.clone();
+            slot_b.name = String::from("root_b");
+            slot_b.label = Some(String::from(Slot::B.label()));
+            slot_b.mount_point = None;
+
+            partitions.push(p);
+            partitions.push(slot_b);
+        }
+        Self::new(partitions)
+    }
+
+    /// The partition marked active on MBR disks, which is the one holding /boot
+    fn boot_index(&self) -> usize {
+        self.partitions
+            .iter()
+            .position(|p| !p.is_root() && p.mount_point.as_deref() == Some(Path::new("/boot")))
+            .unwrap_or_else(|| self.root_index())
+    }
+
+    /// Places the partitions between the given sectors, returning the first and last sector of
+    /// each partition
+    fn allocate(
+        &self,
+        sector_size: u64,
+        first_usable_lba: u64,
+        last_usable_lba: u64,
+    ) -> anyhow::Result<Vec<(u64, u64)>> {
+        let alignment = (PARTITION_ALIGNMENT / sector_size).max(1);
+        let too_small = || {
+            anyhow!(
+                "The partition layout does not fit on the device ({})",
+                Byte::from_bytes(u128::from((last_usable_lba + 1) * sector_size))
+                    .get_appropriate_unit(true)
+            )
+        };
+
+        let place = |rest_sectors: u64| {
+            let mut allocation = Vec::new();
+            let mut next_lba = first_usable_lba;
+            for spec in &self.partitions {
+                let first_lba = next_lba.div_ceil(alignment) * alignment;
+                let sectors = match spec.size {
+                    Some(size) => (size.get_bytes() as u64).div_ceil(sector_size).max(1),
+                    None => rest_sectors,
+                };
+                let last_lba = first_lba + sectors - 1;
+                allocation.push((first_lba, last_lba));
+                next_lba = last_lba + 1;
+            }
+            allocation
+        };
+
+        // The partition without a size takes what the others leave, so the partitions are placed
+        // with it at its smallest first to find out how much that is
+        let smallest = place(alignment);
+        let end = smallest
+            .last()
+            .map_or(first_usable_lba, |(_, last_lba)| *last_lba);
+        if end > last_usable_lba {
+            return Err(too_small());
+        }
+        let free = last_usable_lba - end;
+
+        let allocation = match self.partitions.iter().position(|p| p.size.is_none()) {
+            None => smallest,
+            // The last partition ends right at the end of the usable space
+            Some(i) if i == self.partitions.len() - 1 => place(alignment + free),
+            // Otherwise the rest is rounded down, so the following partitions stay aligned
+            Some(_) => place(alignment + free / alignment * alignment),
+        };
+
+        Ok(allocation)
+    }
+
+    /// Allocates the partitions of the layout on a disk of the given size
+    /// GPT partitions get random GUIDs, and MBR partitions PARTUUIDs derived from a random disk
+    /// signature, so they can be found once udev sees them.
+    pub fn partition_table(
+        &self,
+        table_type: PartitionTableType,
+        sector_size: u64,
+        disk_size: u64,
+    ) -> anyhow::Result<PartitionTable> {
+        self.check_partition_table(table_type)?;
+        let disk_sectors = disk_size / sector_size;
+
+        match table_type {
+            PartitionTableType::Gpt => {
+                let mut table = GptTable::new(sector_size)?;
+                let allocation = self.allocate(
+                    sector_size,
+                    table.first_usable_lba(),
+                    table.last_usable_lba(disk_sectors),
+                )?;
+
+                for (i, (spec, (first_lba, last_lba))) in
+                    self.partitions.iter().zip(allocation).enumerate()
+                {
+                    table.partitions.push(GptPartition {
+                        number: i as u32 + 1,
+                        partition_type: spec.partition_type.guid(),
+                        guid: Guid::random()?,
+                        first_lba,
+                        last_lba,
+                        attributes: spec
+                            .attributes
+                            .iter()
+                            .fold(0, |attributes, attribute| attributes | attribute.bit()),
+                        name: spec.name.clone(),
+                    });
+                }
+
+                Ok(PartitionTable::Gpt(table))
+            }
+            PartitionTableType::Msdos => {
+                let mut table = MbrTable::new(sector_size)?;
+                // The last sector is left alone, since a previous GPT kept its backup header
+                // there. MBR cannot address anything beyond 2^32 sectors.
+                let last_usable_lba = disk_sectors.saturating_sub(2).min(u64::from(u32::MAX));
+                let allocation = self.allocate(sector_size, 1, last_usable_lba)?;
+                let boot_index = self.boot_index();
+
+                for (i, (spec, (first_lba, last_lba))) in
+                    self.partitions.iter().zip(allocation).enumerate()
+                {
+                    table.partitions.push(MbrPartition {
+                        number: i as u32 + 1,
+                        partition_type: spec
+                            .partition_type
+                            .mbr_type()
+                            .expect("Partition type not supported by MBR"),
+                        bootable: i == boot_index,
+                        first_lba: first_lba as u32,
+                        sectors: (last_lba - first_lba + 1) as u32,
+                    });
+                }
+
+                Ok(PartitionTable::Msdos(table))
+            }
+        }
+    }
+}
+
+/// Finds the partitions of an existing device by their partition type
+///
+/// Partitions of types unknown to ALMA are skipped.
+pub fn discover_partitions<'a>(
+    storage_device: &'a StorageDevice,
+) -> anyhow::Result<Vec<(PartitionType, Partition<'a>)>> {
+    let known_partitions: Vec<(PartitionType, u32)> = match storage_device.read_partition_table()? {
+        PartitionTable::Gpt(table) => table
+            .partitions
+            .iter()
+            .filter_map(|p| PartitionType::from_guid(&p.partition_type).map(|t| (t, p.number)))
+            .collect(),
+        PartitionTable::Msdos(table) => table
+            .partitions
+            .iter()
+            .filter_map(|p| PartitionType::from_mbr_type(p.partition_type).map(|t| (t, p.number)))
+            .collect(),
+    };
+
+    known_partitions
+        .into_iter()
+        .map(|(partition_type, number)| Ok((partition_type, storage_device.get_partition(number)?)))
+        .collect()
+}
+
+/// Finds the root partition among the discovered partitions
+pub fn root_partition_index(partitions: &[(PartitionType, Partition)]) -> anyhow::Result<usize> {
+    // Older versions of ALMA created the root partition with the generic Linux filesystem type
+    partitions
+        .iter()
+        .position(|(partition_type, _)| *partition_type == PartitionType::LinuxRoot)
+        .or_else(|| {
+            partitions
+                .iter()
+                .position(|(partition_type, _)| *partition_type == PartitionType::LinuxFilesystem)
+        })
+        .ok_or_else(|| anyhow!("Cannot find the root partition"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_layout_partition_table() {
+        let layout = Layout::default_layout(300, FilesystemType::Ext4, PartitionTableType::Gpt);
+        assert_eq!(layout.root_index(), 2);
+
+        let disk_size = 4 * 1024 * 1024 * 1024;
+        let table = match layout
+            .partition_table(PartitionTableType::Gpt, 512, disk_size)
+            .unwrap()
+        {
+            PartitionTable::Gpt(table) => table,
+            PartitionTable::Msdos(_) => panic!("Expected a GPT"),
+        };
+        let allocation: Vec<(u32, u64, u64, &str)> = table
+            .partitions
+            .iter()
+            .map(|p| (p.number, p.first_lba, p.last_lba, p.name.as_str()))
+            .collect();
+        assert_eq!(
+            allocation,
+            vec![
+                (1, 2048, 616_447, "boot"),
+                (2, 616_448, 618_495, "bios"),
+                (3, 618_496, disk_size / 512 - 34, "root"),
+            ]
+        );
+        assert_eq!(
+            table.partitions[0].partition_type,
+            PartitionType::Esp.guid()
+        );
+        assert!(layout
+            .partition_table(PartitionTableType::Gpt, 512, 200 * 1024 * 1024)
+            .is_err());
+    }
+
+    #[test]
+    fn msdos_partition_table() {
+        let layout = Layout::default_layout(300, FilesystemType::Ext4, PartitionTableType::Msdos);
+        let disk_size = 4 * 1024 * 1024 * 1024;
+        let table = match layout
+            .partition_table(PartitionTableType::Msdos, 512, disk_size)
+            .unwrap()
+        {
+            PartitionTable::Msdos(table) => table,
+            PartitionTable::Gpt(_) => panic!("Expected an MBR"),
+        };
+        let allocation: Vec<(u8, bool, u32, u32)> = table
+            .partitions
+            .iter()
+            .map(|p| (p.partition_type, p.bootable, p.first_lba, p.sectors))
+            .collect();
+        assert_eq!(
+            allocation,
+            vec![
+                (ESP_PARTITION_TYPE, true, 2048, 614_400),
+                (
+                    LINUX_PARTITION_TYPE,
+                    false,
+                    616_448,
+                    (disk_size / 512 - 1 - 616_448) as u32
+                ),
+            ]
+        );
+
+        let gpt_layout = Layout::default_layout(300, FilesystemType::Ext4, PartitionTableType::Gpt);
+        assert!(gpt_layout
+            .check_partition_table(PartitionTableType::Msdos)
+            .is_err());
+    }
+
+    #[test]
+    fn layout_validation() {
+        let root = PartitionSpec::new(
+            "root",
+            None,
+            PartitionType::LinuxRoot,
+            Some(FilesystemType::Ext4),
+            Some("/"),
+        );
+        let home = PartitionSpec::new(
+            "home",
+            None,
+            PartitionType::LinuxHome,
+            Some(FilesystemType::Ext4),
+            Some("/home"),
+        );
+
+        let boot = PartitionSpec::new(
+            "boot",
+            Some(Byte::from_bytes(300 * 1024 * 1024)),
+            PartitionType::Esp,
+            Some(FilesystemType::Vfat),
+            Some("/boot"),
+        );
+
+        assert!(Layout::new(vec![boot.clone(), root.clone()]).is_ok());
+        assert!(Layout::new(vec![root.clone()]).is_err());
+        assert!(Layout::new(vec![boot.clone(), home.clone()]).is_err());
+        assert!(Layout::new(vec![boot.clone(), root.clone(), home.clone()]).is_err());
+
+        let mut sized_root = root;
+        sized_root.size = Some(Byte::from_bytes(1024 * 1024 * 1024));
+        assert!(Layout::new(vec![boot, home, sized_root]).is_ok());
+    }
+
+    #[test]
+    fn ab_slots() {
+        let layout = Layout::default_layout(300, FilesystemType::Btrfs, PartitionTableType::Gpt)
+            .with_ab_slots(Byte::from_bytes(1024 * 1024 * 1024))
+            .unwrap();
+        let names: Vec<&str> = layout
+            .partitions()
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["boot", "bios", "root_a", "root_b"]);
+        assert_eq!(layout.esp_mount_point(), Path::new("/efi"));
+        assert!(layout.boot_on_root());
+        assert!(layout.root().subvolumes().is_empty());
+        assert_eq!(layout.partitions()[3].label.as_deref(), Some("alma_b"));
+        assert_eq!(layout.partitions()[3].size, layout.root().size);
+    }
+
+    #[test]
+    fn data_partition_takes_the_rest() {
+        let layout = Layout::default_layout(300, FilesystemType::Ext4, PartitionTableType::Msdos)
+            .with_data_partition(
+                None,
+                Some(Byte::from_bytes(1024 * 1024 * 1024)),
+                Path::new("/data"),
+            )
+            .unwrap();
+        assert_eq!(layout.root_index(), 2);
+
+        let disk_size = 4 * 1024 * 1024 * 1024;
+        let table = match layout
+            .partition_table(PartitionTableType::Msdos, 512, disk_size)
+            .unwrap()
+        {
+            PartitionTable::Msdos(table) => table,
+            PartitionTable::Gpt(_) => panic!("Expected an MBR"),
+        };
+        let allocation: Vec<(u8, bool, u32, u32)> = table
+            .partitions
+            .iter()
+            .map(|p| (p.partition_type, p.bootable, p.first_lba, p.sectors))
+            .collect();
+        // The data partition is rounded down to whole MiB, so the root partition ends within the
+        // last MiB of the disk
+        assert_eq!(
+            allocation,
+            vec![
+                (BASIC_DATA_PARTITION_TYPE, false, 2048, 5_672_960),
+                (ESP_PARTITION_TYPE, true, 5_675_008, 614_400),
+                (LINUX_PARTITION_TYPE, false, 6_289_408, 2_097_152),
+            ]
+        );
+
+        assert!(
+            Layout::default_layout(300, FilesystemType::Ext4, PartitionTableType::Gpt)
+                .with_data_partition(None, None, Path::new("/data"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn btrfs_default_subvolumes() {
+        let layout = Layout::default_layout(300, FilesystemType::Btrfs, PartitionTableType::Gpt);
+        let root = layout.root();
+        assert_eq!(root.subvolumes().len(), 4);
+        assert_eq!(root.default_subvolume().unwrap().name, "@");
+
+        let ext4_layout =
+            Layout::default_layout(300, FilesystemType::Ext4, PartitionTableType::Gpt);
+        assert!(ext4_layout.root().subvolumes().is_empty());
+    }
+}
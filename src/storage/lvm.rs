@@ -0,0 +1,173 @@
+use super::markers::BlockDevice;
+use crate::process::CommandExt;
+use crate::tool::Tool;
+use anyhow::Context;
+use byte_unit::Byte;
+use log::{debug, warn};
+use std::fs;
+use std::io::Read;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+static LVM_LABEL: &[u8] = b"LABELONE";
+const SECTOR_SIZE: usize = 512;
+
+/// Size of a new logical volume
+#[derive(Debug, Clone, Copy)]
+pub enum VolumeSize {
+    Bytes(Byte),
+    /// Percentage of the whole volume group
+    Percent(u8),
+    /// Whatever is left in the volume group
+    Free,
+}
+
+/// An active LVM volume group, deactivated when dropped
+#[derive(Debug)]
+pub struct VolumeGroup<'t, 'o> {
+    lvm: &'t Tool,
+    name: String,
+    origin: PhantomData<&'o dyn BlockDevice>,
+}
+
+impl<'t, 'o> VolumeGroup<'t, 'o> {
+    /// Creates a volume group with the given device as its only physical volume
+    pub fn create(
+        lvm: &'t Tool,
+        device: &'o dyn BlockDevice,
+        name: &str,
+    ) -> anyhow::Result<VolumeGroup<'t, 'o>> {
+        debug!(
+            "Creating volume group {} in {}",
+            name,
+            device.path().display()
+        );
+        lvm.execute()
+            .args(["pvcreate", "-ff", "-y"])
+            .arg(device.path())
+            .run()
+            .context("Error creating the LVM physical volume")?;
+
+        lvm.execute()
+            .args(["vgcreate", name])
+            .arg(device.path())
+            .run()
+            .context("Error creating the LVM volume group")?;
+
+        Ok(Self {
+            lvm,
+            name: String::from(name),
+            origin: PhantomData,
+        })
+    }
+
+    /// Activates an existing volume group
+    pub fn activate(
+        lvm: &'t Tool,
+        device: &'o dyn BlockDevice,
+        name: &str,
+    ) -> anyhow::Result<VolumeGroup<'t, 'o>> {
+        debug!(
+            "Activating volume group {} in {}",
+            name,
+            device.path().display()
+        );
+        lvm.execute()
+            .args(["vgchange", "-ay", name])
+            .run()
+            .context("Error activating the LVM volume group")?;
+
+        Ok(Self {
+            lvm,
+            name: String::from(name),
+            origin: PhantomData,
+        })
+    }
+
+    pub fn create_volume(&self, name: &str, size: VolumeSize) -> anyhow::Result<LogicalVolume<'_>> {
+        debug!("Creating logical volume {} ({:?})", name, size);
+        let mut command = self.lvm.execute();
+        command.args(["lvcreate", "-y", "-n", name]);
+        match size {
+            VolumeSize::Bytes(size) => command.arg(format!("-L{}b", size.get_bytes())),
+            VolumeSize::Percent(percent) => command.arg(format!("-l{}%VG", percent)),
+            VolumeSize::Free => command.arg("-l100%FREE"),
+        };
+        command
+            .arg(&self.name)
+            .run()
+            .with_context(|| format!("Error creating the {} logical volume", name))?;
+
+        Ok(LogicalVolume::new(self.volume_path(name)))
+    }
+
+    /// Returns a logical volume of the group if it exists
+    pub fn volume(&self, name: &str) -> Option<LogicalVolume<'_>> {
+        let path = self.volume_path(name);
+        if path.exists() {
+            Some(LogicalVolume::new(path))
+        } else {
+            None
+        }
+    }
+
+    fn volume_path(&self, name: &str) -> PathBuf {
+        PathBuf::from("/dev").join(&self.name).join(name)
+    }
+
+    fn _deactivate(&mut self) -> anyhow::Result<()> {
+        debug!("Deactivating volume group {}", self.name);
+        self.lvm
+            .execute()
+            .args(["vgchange", "-an"])
+            .arg(&self.name)
+            .run()
+            .context("Error deactivating the LVM volume group")?;
+
+        Ok(())
+    }
+}
+
+impl<'t, 'o> Drop for VolumeGroup<'t, 'o> {
+    fn drop(&mut self) {
+        if self._deactivate().is_err() {
+            warn!("Error deactivating {}", self.name);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LogicalVolume<'a> {
+    path: PathBuf,
+    origin: PhantomData<&'a VolumeGroup<'a, 'a>>,
+}
+
+impl<'a> LogicalVolume<'a> {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            origin: PhantomData,
+        }
+    }
+}
+
+impl<'a> BlockDevice for LogicalVolume<'a> {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Checks whether the device is an LVM physical volume
+/// The LVM label is in one of the first four sectors
+pub fn is_lvm_device(device: &dyn BlockDevice) -> anyhow::Result<bool> {
+    let mut f = fs::File::open(device.path())
+        .context("Error detecting whether the device is an LVM physical volume")?;
+
+    let mut buffer = [0; SECTOR_SIZE * 4];
+    f.read_exact(&mut buffer)
+        .context("Error detecting whether the device is an LVM physical volume")?;
+
+    Ok(buffer
+        .chunks(SECTOR_SIZE)
+        .any(|sector| sector.starts_with(LVM_LABEL)))
+}
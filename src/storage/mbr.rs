@@ -0,0 +1,220 @@
+use anyhow::{anyhow, Context};
+use std::convert::TryInto;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+pub const LINUX_PARTITION_TYPE: u8 = 0x83;
+pub const ESP_PARTITION_TYPE: u8 = 0xEF;
+/// Also used for NTFS, but that is what Windows expects of exFAT partitions
+pub const BASIC_DATA_PARTITION_TYPE: u8 = 0x07;
+pub const GPT_PROTECTIVE_TYPE: u8 = 0xEE;
+
+const MAX_PARTITIONS: usize = 4;
+const DISK_SIGNATURE_OFFSET: usize = 440;
+const PARTITION_ENTRIES_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+const BOOTABLE: u8 = 0x80;
+/// CHS addresses are meaningless for disks of this century. This value tells the firmware to use
+/// the LBA fields instead
+const CHS_UNUSED: [u8; 3] = [0xFE, 0xFF, 0xFF];
+
+/// A primary partition of an MBR partition table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MbrPartition {
+    /// Position in the partition table, starting from 1
+    pub number: u32,
+    pub partition_type: u8,
+    pub bootable: bool,
+    pub first_lba: u32,
+    pub sectors: u32,
+}
+
+/// A legacy (msdos) partition table, limited to four primary partitions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MbrTable {
+    pub disk_signature: u32,
+    pub sector_size: u64,
+    pub partitions: Vec<MbrPartition>,
+}
+
+impl MbrTable {
+    pub fn new(sector_size: u64) -> anyhow::Result<Self> {
+        let mut signature = [0; 4];
+        fs::File::open("/dev/urandom")
+            .and_then(|mut urandom| urandom.read_exact(&mut signature))
+            .context("Error generating a disk signature")?;
+
+        Ok(Self {
+            disk_signature: u32::from_le_bytes(signature),
+            sector_size,
+            partitions: Vec::new(),
+        })
+    }
+
+    /// The PARTUUID the kernel and udev derive from the disk signature
+    pub fn part_uuid(&self, number: u32) -> String {
+        format!("{:08x}-{:02x}", self.disk_signature, number)
+    }
+
+    /// Writes the partition table to the first sector of the device
+    /// The headers of a previous GPT are wiped, so nothing mistakes the disk for a GPT disk.
+    pub fn write<D: Write + Seek>(&self, device: &mut D) -> anyhow::Result<()> {
+        let mbr = self.sector([0; 512])?;
+
+        let disk_size = device
+            .seek(SeekFrom::End(0))
+            .context("Error querying the size of the device")?;
+        let sector_size = self.sector_size;
+        let empty_sector = vec![0; sector_size as usize];
+        device
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| device.write_all(&mbr))
+            .and_then(|_| device.seek(SeekFrom::Start(sector_size)))
+            .and_then(|_| device.write_all(&empty_sector))
+            .and_then(|_| device.seek(SeekFrom::Start(disk_size - sector_size)))
+            .and_then(|_| device.write_all(&empty_sector))
+            .and_then(|_| device.flush())
+            .context("Error writing the partition table")?;
+
+        Ok(())
+    }
+
+    /// Replaces the partition entries of the existing MBR of the device
+    /// The boot code in the MBR and the gap after it, where GRUB puts its core image, are kept.
+    pub fn update<D: Read + Write + Seek>(&self, device: &mut D) -> anyhow::Result<()> {
+        let mut existing = [0; 512];
+        device
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| device.read_exact(&mut existing))
+            .context("Error reading the partition table")?;
+        let mbr = self.sector(existing)?;
+
+        device
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| device.write_all(&mbr))
+            .and_then(|_| device.flush())
+            .context("Error writing the partition table")?;
+
+        Ok(())
+    }
+
+    /// Puts the disk signature and the partition entries into the given MBR, keeping its boot code
+    fn sector(&self, mut mbr: [u8; 512]) -> anyhow::Result<[u8; 512]> {
+        if self.partitions.len() > MAX_PARTITIONS {
+            return Err(anyhow!(
+                "An MBR partition table holds at most {} partitions",
+                MAX_PARTITIONS
+            ));
+        }
+
+        mbr[DISK_SIGNATURE_OFFSET..DISK_SIGNATURE_OFFSET + 4]
+            .copy_from_slice(&self.disk_signature.to_le_bytes());
+        for byte in &mut mbr[PARTITION_ENTRIES_OFFSET..510] {
+            *byte = 0;
+        }
+        for partition in &self.partitions {
+            if partition.number == 0 || partition.number as usize > MAX_PARTITIONS {
+                return Err(anyhow!("Invalid partition number {}", partition.number));
+            }
+
+            let offset = PARTITION_ENTRIES_OFFSET + (partition.number as usize - 1) * ENTRY_SIZE;
+            let entry = &mut mbr[offset..offset + ENTRY_SIZE];
+            entry[0] = if partition.bootable { BOOTABLE } else { 0 };
+            entry[1..4].copy_from_slice(&CHS_UNUSED);
+            entry[4] = partition.partition_type;
+            entry[5..8].copy_from_slice(&CHS_UNUSED);
+            entry[8..12].copy_from_slice(&partition.first_lba.to_le_bytes());
+            entry[12..16].copy_from_slice(&partition.sectors.to_le_bytes());
+        }
+        mbr[510] = 0x55;
+        mbr[511] = 0xAA;
+        Ok(mbr)
+    }
+
+    /// Reads the MBR partition table of the device
+    /// Fails on GPT disks, which only have a protective MBR.
+    pub fn read<D: Read + Seek>(device: &mut D, sector_size: u64) -> anyhow::Result<Self> {
+        let mut mbr = [0; 512];
+        device
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| device.read_exact(&mut mbr))
+            .context("Error reading the partition table")?;
+
+        if mbr[510..512] != [0x55, 0xAA] {
+            return Err(anyhow!("No partition table found on the device"));
+        }
+
+        let partitions: Vec<MbrPartition> = mbr
+            [PARTITION_ENTRIES_OFFSET..PARTITION_ENTRIES_OFFSET + MAX_PARTITIONS * ENTRY_SIZE]
+            .chunks(ENTRY_SIZE)
+            .enumerate()
+            .filter(|(_, entry)| entry[4] != 0)
+            .map(|(i, entry)| MbrPartition {
+                number: i as u32 + 1,
+                partition_type: entry[4],
+                bootable: entry[0] & BOOTABLE != 0,
+                first_lba: u32::from_le_bytes(entry[8..12].try_into().unwrap()),
+                sectors: u32::from_le_bytes(entry[12..16].try_into().unwrap()),
+            })
+            .collect();
+
+        if partitions
+            .iter()
+            .any(|p| p.partition_type == GPT_PROTECTIVE_TYPE)
+        {
+            return Err(anyhow!("The device has a GPT partition table"));
+        }
+
+        Ok(Self {
+            disk_signature: u32::from_le_bytes(
+                mbr[DISK_SIGNATURE_OFFSET..DISK_SIGNATURE_OFFSET + 4]
+                    .try_into()
+                    .unwrap(),
+            ),
+            sector_size,
+            partitions,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn write_and_read() {
+        let table = MbrTable {
+            disk_signature: 0x1234_abcd,
+            sector_size: 512,
+            partitions: vec![
+                MbrPartition {
+                    number: 1,
+                    partition_type: ESP_PARTITION_TYPE,
+                    bootable: true,
+                    first_lba: 2048,
+                    sectors: 2048,
+                },
+                MbrPartition {
+                    number: 2,
+                    partition_type: LINUX_PARTITION_TYPE,
+                    bootable: false,
+                    first_lba: 4096,
+                    sectors: 12288,
+                },
+            ],
+        };
+
+        let mut disk = Cursor::new(vec![0xFF; 8 * 1024 * 1024]);
+        table.write(&mut disk).unwrap();
+
+        let bytes = disk.get_ref();
+        assert_eq!(bytes[446], BOOTABLE);
+        assert_eq!(bytes[446 + 16], 0);
+        assert!(bytes[512..1024].iter().all(|b| *b == 0));
+        assert!(bytes[bytes.len() - 512..].iter().all(|b| *b == 0));
+
+        assert_eq!(MbrTable::read(&mut disk, 512).unwrap(), table);
+        assert_eq!(table.part_uuid(2), "1234abcd-02");
+    }
+}
@@ -1,16 +1,32 @@
+mod btrfs;
 mod crypt;
 mod filesystem;
+mod gpt;
+mod layout;
 mod loop_device;
+mod lvm;
 mod markers;
+mod mbr;
 mod mount_stack;
 mod partition;
+mod partition_table;
 mod removeable_devices;
 mod storage_device;
 
-pub use crypt::{is_encrypted_device, EncryptedDevice};
-pub use filesystem::{Filesystem, FilesystemType};
+pub use btrfs::{btrfs_uuid, create_subvolumes};
+pub use crypt::{
+    generate_recovery_key, is_encrypted_device, EncryptedDevice, KeySource, LuksKey, LuksOptions,
+    LuksType, Pbkdf,
+};
+pub use filesystem::{filesystem_uuid, Filesystem, FilesystemType};
+pub use layout::{
+    discover_partitions, root_partition_index, Layout, PartitionSpec, PartitionType, Subvolume,
+};
 pub use loop_device::LoopDevice;
+pub use lvm::{is_lvm_device, VolumeGroup, VolumeSize};
 pub use markers::BlockDevice;
 pub use mount_stack::MountStack;
+pub use partition::Partition;
+pub use partition_table::PartitionTableType;
 pub use removeable_devices::get_storage_devices;
-pub use storage_device::StorageDevice;
+pub use storage_device::{open_device_or_image, StorageDevice};
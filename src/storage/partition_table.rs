@@ -0,0 +1,245 @@
+use super::gpt::GptTable;
+use super::mbr::MbrTable;
+use anyhow::anyhow;
+use std::convert::TryFrom;
+use std::io::{Read, Seek, Write};
+use std::str::FromStr;
+
+/// The kind of partition table created on the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionTableType {
+    Gpt,
+    /// Legacy MBR, for old BIOS machines which refuse to boot GPT disks
+    Msdos,
+}
+
+impl FromStr for PartitionTableType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gpt" => Ok(PartitionTableType::Gpt),
+            "msdos" => Ok(PartitionTableType::Msdos),
+            _ => Err(anyhow!("Unknown partition table type: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionTable {
+    Gpt(GptTable),
+    Msdos(MbrTable),
+}
+
+impl PartitionTable {
+    /// Reads the partition table of the device, preferring GPT over MBR
+    pub fn read<D: Read + Seek>(device: &mut D, sector_size: u64) -> anyhow::Result<Self> {
+        GptTable::read(device, sector_size)
+            .map(PartitionTable::Gpt)
+            .or_else(|gpt_error| {
+                MbrTable::read(device, sector_size)
+                    .map(PartitionTable::Msdos)
+                    .map_err(|_| gpt_error)
+            })
+    }
+
+    pub fn write<D: Write + Seek>(&self, device: &mut D) -> anyhow::Result<()> {
+        match self {
+            PartitionTable::Gpt(table) => table.write(device),
+            PartitionTable::Msdos(table) => table.write(device),
+        }
+    }
+
+    /// Rewrites the partition table of a device which already has one, keeping the boot code of
+    /// BIOS bootloaders
+    pub fn update<D: Read + Write + Seek>(&self, device: &mut D) -> anyhow::Result<()> {
+        match self {
+            PartitionTable::Gpt(table) => table.update(device),
+            PartitionTable::Msdos(table) => table.update(device),
+        }
+    }
+
+    pub fn sector_size(&self) -> u64 {
+        match self {
+            PartitionTable::Gpt(table) => table.sector_size,
+            PartitionTable::Msdos(table) => table.sector_size,
+        }
+    }
+
+    /// The number, first and last sector of the partition which ends last on the disk
+    pub fn last_partition(&self) -> Option<(u32, u64, u64)> {
+        match self {
+            PartitionTable::Gpt(table) => table
+                .partitions
+                .iter()
+                .map(|p| (p.number, p.first_lba, p.last_lba))
+                .max_by_key(|(_, _, last_lba)| *last_lba),
+            PartitionTable::Msdos(table) => table
+                .partitions
+                .iter()
+                .map(|p| {
+                    let first_lba = u64::from(p.first_lba);
+                    (p.number, first_lba, first_lba + u64::from(p.sectors) - 1)
+                })
+                .max_by_key(|(_, _, last_lba)| *last_lba),
+        }
+    }
+
+    /// Moves the last sector of a partition
+    pub fn set_partition_end(&mut self, number: u32, last_lba: u64) -> anyhow::Result<()> {
+        let not_found = || anyhow!("There is no partition {}", number);
+        match self {
+            PartitionTable::Gpt(table) => {
+                let partition = table
+                    .partitions
+                    .iter_mut()
+                    .find(|p| p.number == number)
+                    .ok_or_else(not_found)?;
+                partition.last_lba = last_lba;
+            }
+            PartitionTable::Msdos(table) => {
+                let partition = table
+                    .partitions
+                    .iter_mut()
+                    .find(|p| p.number == number)
+                    .ok_or_else(not_found)?;
+                partition.sectors = u32::try_from(last_lba + 1 - u64::from(partition.first_lba))
+                    .map_err(|_| anyhow!("Partition {} is too large for MBR", number))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The size of the smallest disk which holds the partitions and the partition table
+    pub fn required_disk_size(&self) -> u64 {
+        let last_lba = self.last_partition().map_or(0, |(_, _, last_lba)| last_lba);
+        let sectors = match self {
+            // The backup partition array and header follow the last partition
+            PartitionTable::Gpt(table) => last_lba + 2 + table.entries_sectors(),
+            // Writing an MBR clears the last sector of the disk, which must not be in use
+            PartitionTable::Msdos(_) => last_lba + 2,
+        };
+        sectors * self.sector_size()
+    }
+
+    /// The PARTUUIDs of the partitions, in partition number order
+    pub fn part_uuids(&self) -> Vec<String> {
+        match self {
+            PartitionTable::Gpt(table) => table
+                .partitions
+                .iter()
+                .map(|p| p.guid.to_string())
+                .collect(),
+            PartitionTable::Msdos(table) => table
+                .partitions
+                .iter()
+                .map(|p| table.part_uuid(p.number))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::gpt::{GptPartition, Guid};
+    use crate::storage::mbr::{MbrPartition, LINUX_PARTITION_TYPE};
+    use std::io::Cursor;
+
+    #[test]
+    fn shrink_last_partition() {
+        let mut gpt = GptTable::new(512).unwrap();
+        for (number, first_lba, last_lba) in &[(1, 2048, 4095), (2, 4096, 20479)] {
+            gpt.partitions.push(GptPartition {
+                number: *number,
+                partition_type: Guid::random().unwrap(),
+                guid: Guid::random().unwrap(),
+                first_lba: *first_lba,
+                last_lba: *last_lba,
+                attributes: 0,
+                name: String::new(),
+            });
+        }
+        let mut table = PartitionTable::Gpt(gpt);
+        assert_eq!(table.last_partition(), Some((2, 4096, 20479)));
+
+        table.set_partition_end(2, 8191).unwrap();
+        assert_eq!(table.required_disk_size(), (8192 + 33) * 512);
+
+        let mut disk = Cursor::new(vec![0; table.required_disk_size() as usize]);
+        table.write(&mut disk).unwrap();
+        assert_eq!(PartitionTable::read(&mut disk, 512).unwrap(), table);
+
+        let mut mbr = MbrTable::new(512).unwrap();
+        mbr.partitions.push(MbrPartition {
+            number: 1,
+            partition_type: LINUX_PARTITION_TYPE,
+            bootable: true,
+            first_lba: 2048,
+            sectors: 16384,
+        });
+        let mut table = PartitionTable::Msdos(mbr);
+        table.set_partition_end(1, 4095).unwrap();
+        assert_eq!(table.last_partition(), Some((1, 2048, 4095)));
+        assert_eq!(table.required_disk_size(), 4097 * 512);
+    }
+
+    /// Shrinks the last partition of an image of 16 MiB with boot code in front of the first
+    /// partition, the way alma shrink does
+    fn shrink_image(mut table: PartitionTable, boot_code_end: usize) -> (Vec<u8>, Vec<u8>) {
+        let image = tempfile::NamedTempFile::new().unwrap();
+        let mut file = image.reopen().unwrap();
+        file.set_len(16 * 1024 * 1024).unwrap();
+        table.write(&mut file).unwrap();
+
+        let mut bytes = std::fs::read(image.path()).unwrap();
+        let boot_code: Vec<u8> = (0..boot_code_end).map(|i| (i % 251) as u8 + 1).collect();
+        bytes[..440].copy_from_slice(&boot_code[..440]);
+        if boot_code_end > 512 {
+            bytes[512..boot_code_end].copy_from_slice(&boot_code[512..]);
+        }
+        std::fs::write(image.path(), &bytes).unwrap();
+
+        let (number, _, _) = table.last_partition().unwrap();
+        table.set_partition_end(number, 8191).unwrap();
+        let mut file = image.reopen().unwrap();
+        file.set_len(table.required_disk_size()).unwrap();
+        table.update(&mut file).unwrap();
+
+        let shrunk = std::fs::read(image.path()).unwrap();
+        assert_eq!(
+            PartitionTable::read(&mut Cursor::new(&shrunk), 512).unwrap(),
+            table
+        );
+        (boot_code, shrunk)
+    }
+
+    #[test]
+    fn shrinking_keeps_boot_code() {
+        let mut mbr = MbrTable::new(512).unwrap();
+        mbr.partitions.push(MbrPartition {
+            number: 1,
+            partition_type: LINUX_PARTITION_TYPE,
+            bootable: true,
+            first_lba: 2048,
+            sectors: 20480,
+        });
+        // GRUB puts its core image into the gap between the MBR and the first partition
+        let (boot_code, shrunk) = shrink_image(PartitionTable::Msdos(mbr), 2048 * 512);
+        assert_eq!(&shrunk[..440], &boot_code[..440]);
+        assert_eq!(&shrunk[512..2048 * 512], &boot_code[512..]);
+
+        let mut gpt = GptTable::new(512).unwrap();
+        gpt.partitions.push(GptPartition {
+            number: 1,
+            partition_type: Guid::random().unwrap(),
+            guid: Guid::random().unwrap(),
+            first_lba: 2048,
+            last_lba: 20479,
+            attributes: 0,
+            name: String::new(),
+        });
+        let (boot_code, shrunk) = shrink_image(PartitionTable::Gpt(gpt), 440);
+        assert_eq!(&shrunk[..440], &boot_code[..]);
+    }
+}
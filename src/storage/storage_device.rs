@@ -1,10 +1,22 @@
+use super::loop_device::LoopDevice;
 use super::markers::{BlockDevice, Origin};
 use super::partition::Partition;
+use super::partition_table::PartitionTable;
 use anyhow::{anyhow, Context};
 use log::debug;
-use std::fs::read_to_string;
+use std::fs::{self, read_to_string, OpenOptions};
 use std::marker::PhantomData;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Asks the kernel to re-read the partition table
+nix::ioctl_none!(blkrrpart, 0x12, 95);
+
+/// sysfs always counts the size of block devices in 512 byte sectors
+const SYSFS_SECTOR_SIZE: u64 = 512;
+const PARTITION_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 #[derive(Debug)]
 pub struct StorageDevice<'a> {
@@ -13,8 +25,32 @@ pub struct StorageDevice<'a> {
     origin: PhantomData<&'a dyn Origin>,
 }
 
+/// Opens the block device at the given path. An image file is attached to a loop device first,
+/// which has to be kept until the storage device is dropped
+/// Only regular files are attached, so a block device which fails the removable check stays
+/// refused instead of being opened through a loop device.
+pub fn open_device_or_image(
+    path: &Path,
+    allow_non_removable: bool,
+) -> anyhow::Result<(Option<LoopDevice>, StorageDevice<'_>)> {
+    match StorageDevice::from_path(path, allow_non_removable) {
+        Ok(storage_device) => Ok((None, storage_device)),
+        Err(err) if !fs::metadata(path).is_ok_and(|metadata| metadata.is_file()) => Err(err),
+        Err(_) => {
+            let loop_device = LoopDevice::create(path)?;
+            let storage_device = StorageDevice::open(loop_device.path(), allow_non_removable)?;
+            Ok((Some(loop_device), storage_device))
+        }
+    }
+}
+
 impl<'a> StorageDevice<'a> {
     pub fn from_path(path: &'a Path, allow_non_removable: bool) -> anyhow::Result<Self> {
+        Self::open(path, allow_non_removable)
+    }
+
+    /// Like from_path, without tying the storage device to the path
+    fn open(path: &Path, allow_non_removable: bool) -> anyhow::Result<Self> {
         debug!("path: {:?}", path);
         let path = path
             .canonicalize()
@@ -69,27 +105,139 @@ impl<'a> StorageDevice<'a> {
         path.exists()
     }
 
-    pub fn get_partition(&self, index: u8) -> anyhow::Result<Partition> {
-        let name = if self
-            .name
-            .chars()
-            .rev()
-            .next()
-            .expect("Storage device name is empty")
-            .is_digit(10)
-        {
-            format!("{}p{}", self.name, index)
-        } else {
-            format!("{}{}", self.name, index)
-        };
-        let mut path = PathBuf::from("/dev");
-        path.push(name);
+    fn read_sys_value(&self, name: &str) -> anyhow::Result<u64> {
+        let path = self.sys_path().join(name);
+        read_to_string(&path)
+            .context("Error querying information about the block device")?
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid value in {}", path.display()))
+    }
+
+    /// Size of the device in bytes
+    pub fn size(&self) -> anyhow::Result<u64> {
+        Ok(self.read_sys_value("size")? * SYSFS_SECTOR_SIZE)
+    }
+
+    /// The sector size used for addressing the partition table
+    pub fn sector_size(&self) -> anyhow::Result<u64> {
+        self.read_sys_value("queue/logical_block_size")
+    }
+
+    pub fn read_partition_table(&self) -> anyhow::Result<PartitionTable> {
+        let mut device = fs::File::open(&self.path).context("Error opening the block device")?;
+        let table = PartitionTable::read(&mut device, self.sector_size()?)?;
+        debug!("Partition table of {}: {:?}", self.name, table);
+        Ok(table)
+    }
+
+    /// Replaces the partition table of the device and lets the kernel know about it
+    pub fn write_partition_table(&self, table: &PartitionTable) -> anyhow::Result<()> {
+        debug!("Writing partition table to {}: {:?}", self.name, table);
+        let mut device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.path)
+            .context("Error opening the block device")?;
+        table.write(&mut device)?;
+        device
+            .sync_all()
+            .context("Error writing the partition table")?;
+
+        unsafe { blkrrpart(device.as_raw_fd()) }.context(
+            "Error re-reading the partition table. Make sure no partition of the device is in use",
+        )?;
+
+        Ok(())
+    }
+
+    /// Waits until the partitions with the given PARTUUIDs show up, in partition number order
+    /// The kernel re-reads the partition table asynchronously and udev creates the device nodes
+    /// after that, which can take a while on slow USB hubs.
+    pub fn wait_for_partitions(
+        &self,
+        part_uuids: &[String],
+        timeout: Duration,
+    ) -> anyhow::Result<Vec<Partition<'_>>> {
+        let start = Instant::now();
+        loop {
+            let partitions = part_uuids
+                .iter()
+                .enumerate()
+                .map(|(i, part_uuid)| self.find_partition(i as u32 + 1, part_uuid))
+                .collect::<anyhow::Result<Option<Vec<_>>>>()?;
+
+            if let Some(partitions) = partitions {
+                return Ok(partitions);
+            }
 
-        debug!("Partition {} for {} is in {:?}", index, self.name, path);
-        if !path.exists() {
-            return Err(anyhow!("Partition {} does not exist", index));
+            if start.elapsed() > timeout {
+                return Err(anyhow!(
+                    "Timed out waiting for the partitions of {} to appear",
+                    self.name
+                ));
+            }
+            thread::sleep(PARTITION_POLL_INTERVAL);
         }
-        Ok(Partition::new::<Self>(path))
+    }
+
+    /// Finds a partition through udev's by-partuuid links, or through sysfs when udev does not
+    /// maintain them
+    fn find_partition(
+        &self,
+        number: u32,
+        part_uuid: &str,
+    ) -> anyhow::Result<Option<Partition<'_>>> {
+        let by_partuuid = Path::new("/dev/disk/by-partuuid");
+        if !by_partuuid.exists() {
+            return self.sysfs_partition(number);
+        }
+
+        let link = by_partuuid.join(part_uuid);
+        if !link.exists() {
+            return Ok(None);
+        }
+        let path = link
+            .canonicalize()
+            .with_context(|| format!("Error resolving {}", link.display()))?;
+
+        // The link has to point to this device, in case another disk has the same PARTUUID
+        Ok(self
+            .sysfs_partition(number)?
+            .filter(|partition| partition.path() == path))
+    }
+
+    pub fn get_partition(&self, number: u32) -> anyhow::Result<Partition<'_>> {
+        self.sysfs_partition(number)?
+            .ok_or_else(|| anyhow!("Partition {} does not exist", number))
+    }
+
+    /// Finds a partition by its number in /sys/block/<device>/<partition>/partition
+    /// Partition names differ between sdX, nvme, mmcblk and loop devices, so they are not guessed
+    fn sysfs_partition(&self, number: u32) -> anyhow::Result<Option<Partition<'_>>> {
+        let entries = fs::read_dir(self.sys_path())
+            .context("Error querying information about the block device")?;
+
+        for entry in entries {
+            let entry = entry.context("Error querying information about the block device")?;
+            let partition_number = match read_to_string(entry.path().join("partition")) {
+                Ok(partition_number) => partition_number,
+                Err(_) => continue,
+            };
+            if partition_number.trim() != number.to_string() {
+                continue;
+            }
+
+            let path = Path::new("/dev").join(entry.file_name());
+            debug!("Partition {} for {} is in {:?}", number, self.name, path);
+            return Ok(if path.exists() {
+                Some(Partition::new::<Self>(path))
+            } else {
+                None
+            });
+        }
+
+        Ok(None)
     }
 }
 
@@ -100,3 +248,34 @@ impl<'a> BlockDevice for StorageDevice<'a> {
 }
 
 impl<'a> Origin for StorageDevice<'a> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_removable_block_device_is_refused() {
+        // Any fixed disk of the machine running the tests will do
+        let disk = fs::read_dir("/sys/block")
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .find(|sys_path| {
+                !sys_path.join("loop").exists()
+                    && read_to_string(sys_path.join("removable")).is_ok_and(|r| r == "0\n")
+                    && Path::new("/dev")
+                        .join(sys_path.file_name().unwrap())
+                        .exists()
+            });
+        let disk = match disk {
+            Some(sys_path) => Path::new("/dev").join(sys_path.file_name().unwrap()),
+            None => return,
+        };
+
+        let err = open_device_or_image(&disk, false).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("neither removable nor a loop device"));
+    }
+}
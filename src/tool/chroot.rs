@@ -1,60 +1,176 @@
-use super::mount;
 use super::Tool;
+use super::{fstab_mount_entries, fstab_subvolumes, mount, read_fstab, MountEntry};
+use crate::ab;
 use crate::args;
-use crate::constants::{BOOT_PARTITION_INDEX, ROOT_PARTITION_INDEX};
+use crate::constants;
+use crate::initcpio;
 use crate::process::CommandExt;
 use crate::storage;
-use crate::storage::{is_encrypted_device, EncryptedDevice};
-use crate::storage::{BlockDevice, Filesystem, FilesystemType, LoopDevice};
-use anyhow::Context;
-use log::info;
-
+use crate::storage::{
+    btrfs_uuid, discover_partitions, root_partition_index, BlockDevice, Filesystem, FilesystemType,
+    PartitionType,
+};
+use crate::storage::{is_encrypted_device, EncryptedDevice, KeySource};
+use crate::storage::{is_lvm_device, VolumeGroup};
+use anyhow::{anyhow, Context};
+use log::{info, warn};
+use std::path::PathBuf;
 use tempfile::tempdir;
 
 /// Use arch-chroot to chroot to the given device
-/// Also handles encrypted root partitions (detected by checking for the LUKS magic header)
+/// The root partition is located by its GPT partition type. The other filesystems are mounted where
+/// the fstab of the installation puts them, falling back to the discoverable partition types for
+/// those it does not list
+/// Also handles encrypted root partitions (detected by checking for the LUKS magic header) and
+/// LVM volume groups inside them, which are activated for the duration of the chroot
+/// Roots which boot under a tmpfs overlay are mounted read-write, so changes persist
+/// On devices with A/B slots the active slot is mounted
 pub fn chroot(command: args::ChrootCommand) -> anyhow::Result<()> {
     let arch_chroot = Tool::find("arch-chroot")?;
+    let blkid = Tool::find("blkid")?;
     let cryptsetup;
+    let lvm;
 
-    let loop_device: Option<LoopDevice>;
-    let storage_device =
-        match storage::StorageDevice::from_path(&command.block_device, command.allow_non_removable)
-        {
-            Ok(b) => b,
-            Err(_) => {
-                loop_device = Some(LoopDevice::create(&command.block_device)?);
-                storage::StorageDevice::from_path(
-                    loop_device.as_ref().expect("loop device not found").path(),
-                    command.allow_non_removable,
-                )?
-            }
-        };
+    let (_loop_device, storage_device) =
+        storage::open_device_or_image(&command.block_device, command.allow_non_removable)?;
     let mount_point = tempdir().context("Error creating a temporary directory")?;
 
-    let boot_partition = storage_device.get_partition(BOOT_PARTITION_INDEX)?;
-    let boot_filesystem = Filesystem::from_partition(&boot_partition, FilesystemType::Vfat);
+    let partitions = discover_partitions(&storage_device)?;
+    let slots = ab::slot_partitions(&partitions);
+    let root_index = match slots {
+        Some(slots) => {
+            let esp = &partitions
+                .iter()
+                .find(|(partition_type, _)| *partition_type == PartitionType::Esp)
+                .ok_or_else(|| anyhow!("Cannot find the EFI system partition"))?
+                .1;
+            let slot =
+                ab::read_env(&Filesystem::from_partition(esp, FilesystemType::Vfat))?.active_slot();
+            info!("Using the active slot {}", slot.name());
+            slots[slot.index()]
+        }
+        None => root_partition_index(&partitions)?,
+    };
+    let root_partition_base = &partitions[root_index].1;
 
-    let root_partition_base = storage_device.get_partition(ROOT_PARTITION_INDEX)?;
-    let encrypted_root = if is_encrypted_device(&root_partition_base)? {
+    let encrypted_root = if is_encrypted_device(root_partition_base)? {
+        if let KeySource::Generate(_) = command.luks_key {
+            return Err(anyhow!(
+                "A key cannot be generated for an existing encrypted device"
+            ));
+        }
         cryptsetup = Some(Tool::find("cryptsetup")?);
         Some(EncryptedDevice::open(
             cryptsetup.as_ref().expect("cryptsetup not found"),
-            &root_partition_base,
+            root_partition_base,
             "alma_root".into(),
+            &command.luks_key.resolve(None)?,
         )?)
     } else {
         None
     };
 
-    let root_partition = if let Some(e) = encrypted_root.as_ref() {
-        e as &dyn BlockDevice
+    let root_container = match &encrypted_root {
+        Some(e) => e as &dyn BlockDevice,
+        None => root_partition_base as &dyn BlockDevice,
+    };
+
+    let volume_group = if is_lvm_device(root_container)? {
+        lvm = Some(Tool::find("lvm")?);
+        Some(VolumeGroup::activate(
+            lvm.as_ref().expect("lvm not found"),
+            root_container,
+            constants::LVM_VOLUME_GROUP,
+        )?)
     } else {
-        &root_partition_base as &dyn BlockDevice
+        None
+    };
+    let logical_volumes = match &volume_group {
+        Some(volume_group) => Some((
+            volume_group
+                .volume("root")
+                .ok_or_else(|| anyhow!("The volume group has no root logical volume"))?,
+            volume_group.volume("home"),
+        )),
+        None => None,
+    };
+
+    let root_block = match &logical_volumes {
+        Some((root, _)) => root as &dyn BlockDevice,
+        None => root_container,
     };
-    let root_filesystem = Filesystem::from_partition(root_partition, FilesystemType::Ext4);
+    let root_filesystem = Filesystem::from_partition(
+        root_block,
+        FilesystemType::detect(root_block)?
+            .ok_or_else(|| anyhow!("Cannot detect the filesystem of the root partition"))?,
+    );
+    let root_btrfs_uuid = btrfs_uuid(root_block)?;
+
+    // Filesystems besides the root, with the mount point of their type in case the fstab does not
+    // list them
+    let mut filesystems = Vec::new();
+    for (i, (partition_type, partition)) in partitions.iter().enumerate() {
+        if i == root_index {
+            continue;
+        }
+
+        // Each slot has its own /boot
+        let fallback = match (partition_type, slots) {
+            (PartitionType::Esp, Some(_)) => Some(ab::ESP_MOUNT_POINT),
+            _ => partition_type
+                .discoverable_mount_point()
+                .filter(|mount_point| *mount_point != "/"),
+        };
+
+        match FilesystemType::detect(partition)? {
+            Some(fs_type) => {
+                filesystems.push((fallback, Filesystem::from_partition(partition, fs_type)))
+            }
+            None if fallback.is_some() => warn!(
+                "Cannot detect the filesystem of {}. It will not be mounted",
+                partition.path().display()
+            ),
+            None => (),
+        }
+    }
+
+    if let Some((_, Some(home))) = &logical_volumes {
+        match FilesystemType::detect(home)? {
+            Some(fs_type) => {
+                filesystems.push((Some("/home"), Filesystem::from_partition(home, fs_type)))
+            }
+            None => warn!("Cannot detect the filesystem of the home logical volume"),
+        }
+    }
+
+    // A btrfs root is mounted through its default subvolume, which contains the fstab
+    let root_mount_stack = mount(
+        mount_point.path(),
+        &[MountEntry::new(PathBuf::from("/"), &root_filesystem)],
+    )?;
+    let fstab = read_fstab(mount_point.path())?;
+
+    let mut mount_entries = fstab_mount_entries(&blkid, &fstab, &filesystems);
+    if let Some(uuid) = &root_btrfs_uuid {
+        mount_entries.extend(fstab_subvolumes(&fstab, uuid).into_iter().map(|subvolume| {
+            MountEntry {
+                mount_point: subvolume.mount_point,
+                filesystem: &root_filesystem,
+                subvolume: Some(subvolume.name),
+            }
+        }));
+    }
+    let mount_stack = mount(mount_point.path(), &mount_entries)?;
 
-    let mount_stack = mount(mount_point.path(), &boot_filesystem, &root_filesystem)?;
+    // The overlay only exists at boot, so the chroot sees and changes the root filesystem itself
+    if mount_point
+        .path()
+        .join("etc/initcpio/hooks")
+        .join(initcpio::OVERLAY_HOOK.name)
+        .exists()
+    {
+        info!("The root filesystem is read-only at boot. Changes made here are kept");
+    }
 
     arch_chroot
         .execute()
@@ -70,6 +186,7 @@ pub fn chroot(command: args::ChrootCommand) -> anyhow::Result<()> {
 
     info!("Unmounting filesystems");
     mount_stack.umount()?;
+    root_mount_stack.umount()?;
 
     Ok(())
 }
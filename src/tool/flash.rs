@@ -0,0 +1,504 @@
+use super::Tool;
+use crate::args;
+use crate::bmap::Bmap;
+use crate::storage::{BlockDevice, StorageDevice};
+use anyhow::{anyhow, Context};
+use byte_unit::Byte;
+use console::style;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::{info, warn};
+use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+use sha2::{Digest, Sha256};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Stdio};
+use std::thread;
+
+const BUFFER_SIZE: usize = 1024 * 1024;
+static ZSTD_MAGIC: &[u8] = &[0x28, 0xB5, 0x2F, 0xFD];
+static XZ_MAGIC: &[u8] = &[0xFD, 0x37, 0x7A, 0x58, 0x5A, 0x00];
+
+/// The decompressor for the image, detected by its magic bytes. None for raw images
+fn decompressor_name(file: &mut fs::File) -> anyhow::Result<Option<&'static str>> {
+    let mut magic = [0; 6];
+    let magic_length = file.read(&mut magic).context("Error reading the image")?;
+    file.seek(SeekFrom::Start(0))
+        .context("Error reading the image")?;
+
+    if magic[..magic_length].starts_with(ZSTD_MAGIC) {
+        Ok(Some("zstd"))
+    } else if magic[..magic_length].starts_with(XZ_MAGIC) {
+        Ok(Some("xz"))
+    } else {
+        Ok(None)
+    }
+}
+
+/// The size of the image if it is raw. Compressed sizes are unknown without decompressing
+fn raw_image_size(path: &Path) -> anyhow::Result<Option<u64>> {
+    let mut file = fs::File::open(path).context("Error opening the image")?;
+    match decompressor_name(&mut file)? {
+        Some(_) => Ok(None),
+        None => Ok(Some(
+            file.metadata().context("Error reading the image")?.len(),
+        )),
+    }
+}
+
+/// A stream of the uncompressed image data
+struct ImageReader {
+    reader: Box<dyn Read>,
+    decompressor: Option<Child>,
+    position: u64,
+}
+
+impl ImageReader {
+    /// Opens a raw, zstd or xz compressed image, detected by its magic bytes
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut file = fs::File::open(path).context("Error opening the image")?;
+        match decompressor_name(&mut file)? {
+            Some(name) => {
+                let mut child = Tool::find(name)?
+                    .execute()
+                    .arg("-dc")
+                    .stdin(file)
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .context("Error starting the decompressor")?;
+                let stdout = child.stdout.take().expect("Decompressor has no stdout");
+                Ok(Self {
+                    reader: Box::new(stdout),
+                    decompressor: Some(child),
+                    position: 0,
+                })
+            }
+            None => Ok(Self {
+                reader: Box::new(file),
+                decompressor: None,
+                position: 0,
+            }),
+        }
+    }
+
+    /// Skips ahead to the given offset. Compressed streams cannot seek, so the data is discarded
+    fn skip_to(&mut self, offset: u64) -> anyhow::Result<()> {
+        let skipped = io::copy(
+            &mut (&mut self.reader).take(offset - self.position),
+            &mut io::sink(),
+        )
+        .context("Error reading the image")?;
+        self.position += skipped;
+
+        if self.position != offset {
+            return Err(anyhow!("The image is shorter than its block map"));
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        let read = self
+            .reader
+            .read(buffer)
+            .context("Error reading the image")?;
+        self.position += read as u64;
+        Ok(read)
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        // Closing the pipe first lets a decompressor with unread output exit
+        drop(self.reader);
+        if let Some(mut decompressor) = self.decompressor {
+            let status = decompressor
+                .wait()
+                .context("Error decompressing the image")?;
+            if !status.success() {
+                return Err(anyhow!("Error decompressing the image: {}", status));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The block map next to the image: almatest.img.bmap for almatest.img or almatest.img.zst
+fn default_bmap_path(image: &Path) -> PathBuf {
+    let base = match image.extension().and_then(OsStr::to_str) {
+        Some("zst") | Some("xz") => image.with_extension(""),
+        _ => image.to_path_buf(),
+    };
+    let mut name = base.into_os_string();
+    name.push(".bmap");
+    PathBuf::from(name)
+}
+
+fn progress_bar(total: Option<u64>, device_name: String) -> ProgressBar {
+    let progress = match total {
+        Some(total) => {
+            let bar = ProgressBar::new(total);
+            bar.set_style(ProgressStyle::default_bar().template(
+                "{prefix} {msg:9} [{wide_bar}] {bytes}/{total_bytes} ({binary_bytes_per_sec}, {eta})",
+            ));
+            bar
+        }
+        None => {
+            let spinner = ProgressBar::new_spinner();
+            spinner.set_style(
+                ProgressStyle::default_spinner()
+                    .template("{prefix} {msg:9} {bytes} ({binary_bytes_per_sec})"),
+            );
+            spinner
+        }
+    };
+    progress.set_prefix(device_name);
+    progress
+}
+
+/// Copies `length` bytes (or everything, if no length is given) from the image to the device
+/// Returns the number of bytes written
+fn copy_range(
+    image: &mut ImageReader,
+    device: &mut fs::File,
+    length: Option<u64>,
+    hasher: &mut Sha256,
+    progress: &ProgressBar,
+) -> anyhow::Result<u64> {
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut written = 0;
+
+    loop {
+        let wanted = match length {
+            Some(length) if written == length => break,
+            Some(length) => (length - written).min(BUFFER_SIZE as u64) as usize,
+            None => BUFFER_SIZE,
+        };
+        let read = image.read(&mut buffer[..wanted])?;
+        if read == 0 {
+            if length.is_some() {
+                return Err(anyhow!("The image is shorter than its block map"));
+            }
+            break;
+        }
+
+        device
+            .write_all(&buffer[..read])
+            .context("Error writing to the device")?;
+        hasher.update(&buffer[..read]);
+        written += read as u64;
+        progress.inc(read as u64);
+    }
+
+    Ok(written)
+}
+
+/// Computes the SHA-256 of a byte range of the device
+fn device_checksum(
+    device: &mut fs::File,
+    start: u64,
+    length: u64,
+    progress: &ProgressBar,
+) -> anyhow::Result<String> {
+    device
+        .seek(SeekFrom::Start(start))
+        .context("Error reading from the device")?;
+
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut remaining = length;
+    while remaining > 0 {
+        let chunk = &mut buffer[..remaining.min(BUFFER_SIZE as u64) as usize];
+        device
+            .read_exact(chunk)
+            .context("Error reading from the device")?;
+        hasher.update(&chunk);
+        remaining -= chunk.len() as u64;
+        progress.inc(chunk.len() as u64);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Writes the image to one device and reads it back to verify it, reporting through `progress`
+/// Only the blocks listed in the block map are written when there is one.
+fn flash_device(
+    image_path: &Path,
+    bmap: Option<&Bmap>,
+    device_path: &Path,
+    verify: bool,
+    progress: &ProgressBar,
+) -> anyhow::Result<()> {
+    let mut image = ImageReader::open(image_path)?;
+
+    // O_EXCL fails if any partition of the device is mounted
+    let mut device = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(nix::libc::O_EXCL)
+        .open(device_path)
+        .context("Error opening the device. Make sure none of its partitions is mounted")?;
+
+    progress.set_message("Writing");
+    let mut written_checksums = Vec::new();
+    match bmap {
+        Some(bmap) => {
+            for range in &bmap.ranges {
+                let (start, end) = bmap.byte_range(range);
+                image.skip_to(start)?;
+                device
+                    .seek(SeekFrom::Start(start))
+                    .context("Error writing to the device")?;
+
+                let mut hasher = Sha256::new();
+                copy_range(
+                    &mut image,
+                    &mut device,
+                    Some(end - start),
+                    &mut hasher,
+                    progress,
+                )?;
+                if format!("{:x}", hasher.finalize()) != range.checksum {
+                    return Err(anyhow!(
+                        "The image does not match its block map at blocks {}-{}",
+                        range.first,
+                        range.last
+                    ));
+                }
+                written_checksums.push((start, end - start, range.checksum.clone()));
+            }
+        }
+        None => {
+            let mut hasher = Sha256::new();
+            let written = copy_range(&mut image, &mut device, None, &mut hasher, progress)?;
+            written_checksums.push((0, written, format!("{:x}", hasher.finalize())));
+        }
+    }
+    image.finish()?;
+
+    progress.set_message("Syncing");
+    device.sync_all().context("Error writing to the device")?;
+
+    if !verify {
+        return Ok(());
+    }
+
+    // Read from the device itself rather than from the page cache
+    posix_fadvise(
+        device.as_raw_fd(),
+        0,
+        0,
+        PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+    )
+    .context("Error dropping the cached data of the device")?;
+
+    let total = written_checksums.iter().map(|(_, length, _)| length).sum();
+    progress.set_message("Verifying");
+    progress.set_length(total);
+    progress.set_position(0);
+    for (start, length, checksum) in &written_checksums {
+        if device_checksum(&mut device, *start, *length, progress)? != *checksum {
+            return Err(anyhow!(
+                "Verification failed: the data at offset {} differs from the image. The device \
+                 may be faulty",
+                start
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes an image to one or more removable devices in parallel and verifies them
+pub fn flash(command: args::FlashCommand) -> anyhow::Result<()> {
+    let bmap_path = match (&command.bmap, command.no_bmap) {
+        (_, true) => None,
+        (Some(path), false) => Some(path.clone()),
+        (None, false) => Some(default_bmap_path(&command.image)).filter(|path| path.exists()),
+    };
+    let bmap = bmap_path
+        .map(|path| {
+            info!("Using the block map {}", path.display());
+            fs::read_to_string(&path)
+                .with_context(|| format!("Error reading {}", path.display()))
+                .and_then(|xml| Bmap::from_xml(&xml))
+        })
+        .transpose()?;
+
+    let image_size = match &bmap {
+        Some(bmap) => Some(bmap.image_size),
+        None => raw_image_size(&command.image)?,
+    };
+
+    let device_paths = if command.all {
+        let devices = crate::storage::get_storage_devices(false)?;
+        if devices.is_empty() {
+            return Err(anyhow!("There are no removable devices"));
+        }
+        devices
+            .iter()
+            .map(|device| Path::new("/dev").join(&device.name))
+            .collect()
+    } else if command.block_devices.is_empty() {
+        vec![crate::select_block_device(command.allow_non_removable)?]
+    } else {
+        command.block_devices.clone()
+    };
+
+    // Check every device before writing to any of them
+    let mut device_files = Vec::new();
+    for device_path in &device_paths {
+        let storage_device = StorageDevice::from_path(device_path, command.allow_non_removable)
+            .with_context(|| format!("Error opening {}", device_path.display()))?;
+        if let Some(image_size) = image_size {
+            if image_size > storage_device.size()? {
+                return Err(anyhow!(
+                    "The image ({}) is larger than {} ({})",
+                    Byte::from_bytes(u128::from(image_size)).get_appropriate_unit(true),
+                    device_path.display(),
+                    Byte::from_bytes(u128::from(storage_device.size()?)).get_appropriate_unit(true)
+                ));
+            }
+        }
+        let device_file = storage_device.path().to_path_buf();
+        if device_files.contains(&device_file) {
+            return Err(anyhow!(
+                "{} was given more than once",
+                device_path.display()
+            ));
+        }
+        device_files.push(device_file);
+    }
+
+    info!(
+        "Writing {} to {} device(s)",
+        command.image.display(),
+        device_files.len()
+    );
+    if command.no_verify {
+        warn!("Skipping verification");
+    }
+
+    let multi_progress = MultiProgress::new();
+    let total = bmap.as_ref().map(Bmap::mapped_bytes).or(image_size);
+    let results: Vec<anyhow::Result<()>> = thread::scope(|scope| {
+        let handles: Vec<_> = device_files
+            .iter()
+            .zip(&device_paths)
+            .map(|(device_file, device_path)| {
+                let progress =
+                    multi_progress.add(progress_bar(total, device_path.display().to_string()));
+                let image = &command.image;
+                let bmap = bmap.as_ref();
+                let verify = !command.no_verify;
+                scope.spawn(move || {
+                    let result = flash_device(image, bmap, device_file, verify, &progress);
+                    match &result {
+                        Ok(()) => progress.finish_with_message("Done"),
+                        Err(_) => progress.abandon_with_message("Failed"),
+                    }
+                    result
+                })
+            })
+            .collect();
+
+        // Draws the progress bars until every device is done
+        if let Err(e) = multi_progress.join() {
+            warn!("Error drawing the progress: {}", e);
+        }
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("Flashing thread panicked"))
+            .collect()
+    });
+
+    println!();
+    let mut failures = 0;
+    for (device_path, result) in device_paths.iter().zip(&results) {
+        match result {
+            Ok(()) => println!("{} {}", style("PASS").green().bold(), device_path.display()),
+            Err(e) => {
+                failures += 1;
+                println!(
+                    "{} {}: {:#}",
+                    style("FAIL").red().bold(),
+                    device_path.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    if failures > 0 {
+        return Err(anyhow!(
+            "{} of {} device(s) failed",
+            failures,
+            device_paths.len()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bmap::BmapRange;
+
+    #[test]
+    fn bmap_next_to_image() {
+        assert_eq!(
+            default_bmap_path(Path::new("/tmp/almatest.img.zst")),
+            Path::new("/tmp/almatest.img.bmap")
+        );
+        assert_eq!(
+            default_bmap_path(Path::new("almatest.img")),
+            Path::new("almatest.img.bmap")
+        );
+    }
+
+    #[test]
+    fn flash_mapped_blocks() {
+        let data: Vec<u8> = (0..3 * 4096).map(|i| (i % 251) as u8).collect();
+        let checksum = |bytes: &[u8]| format!("{:x}", Sha256::digest(bytes));
+        let bmap = Bmap {
+            image_size: data.len() as u64,
+            block_size: 4096,
+            ranges: vec![BmapRange {
+                first: 2,
+                last: 2,
+                checksum: checksum(&data[8192..]),
+            }],
+        };
+
+        let image = tempfile::NamedTempFile::new().unwrap();
+        fs::write(image.path(), &data).unwrap();
+        let device = tempfile::NamedTempFile::new().unwrap();
+        fs::write(device.path(), vec![0; data.len()]).unwrap();
+
+        let progress = ProgressBar::hidden();
+        flash_device(image.path(), Some(&bmap), device.path(), true, &progress).unwrap();
+        let written = fs::read(device.path()).unwrap();
+        assert!(written[..8192].iter().all(|b| *b == 0));
+        assert_eq!(written[8192..], data[8192..]);
+
+        flash_device(image.path(), None, device.path(), true, &progress).unwrap();
+        assert_eq!(fs::read(device.path()).unwrap(), data);
+
+        let corrupted = Bmap {
+            ranges: vec![BmapRange {
+                checksum: checksum(&data[..4096]),
+                ..bmap.ranges[0].clone()
+            }],
+            ..bmap
+        };
+        assert!(flash_device(
+            image.path(),
+            Some(&corrupted),
+            device.path(),
+            true,
+            &progress
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,68 @@
+use super::Tool;
+use crate::args::{self, LuksAction};
+use crate::storage;
+use crate::storage::{discover_partitions, root_partition_index};
+use crate::storage::{is_encrypted_device, EncryptedDevice, KeySource};
+use anyhow::anyhow;
+use log::info;
+
+/// Manages the keys of the encrypted root partition of an existing device or image
+pub fn luks(command: args::LuksCommand) -> anyhow::Result<()> {
+    let cryptsetup = Tool::find("cryptsetup")?;
+
+    let (_loop_device, storage_device) =
+        storage::open_device_or_image(&command.block_device, command.allow_non_removable)?;
+
+    let partitions = discover_partitions(&storage_device)?;
+    let root_partition = &partitions[root_partition_index(&partitions)?].1;
+    if !is_encrypted_device(root_partition)? {
+        return Err(anyhow!("The root partition is not encrypted"));
+    }
+
+    if let KeySource::Generate(_) = command.luks_key {
+        return Err(anyhow!(
+            "The current key of an existing encrypted device cannot be generated"
+        ));
+    }
+    let reads_new_key_from_stdin = match &command.action {
+        LuksAction::Add { new_key, .. } | LuksAction::Change { new_key } => {
+            *new_key == KeySource::Stdin
+        }
+        LuksAction::Remove => false,
+    };
+    if reads_new_key_from_stdin && command.luks_key == KeySource::Stdin {
+        return Err(anyhow!(
+            "The current key and the new key cannot both be read from the standard input"
+        ));
+    }
+
+    let key = command.luks_key.resolve(None)?;
+
+    match command.action {
+        LuksAction::Add { new_key, slot } => {
+            EncryptedDevice::add_key(
+                &cryptsetup,
+                root_partition,
+                &key,
+                &new_key.resolve(None)?,
+                slot,
+            )?;
+            info!("Key added");
+        }
+        LuksAction::Change { new_key } => {
+            EncryptedDevice::change_key(
+                &cryptsetup,
+                root_partition,
+                &key,
+                &new_key.resolve(None)?,
+            )?;
+            info!("Key changed");
+        }
+        LuksAction::Remove => {
+            EncryptedDevice::remove_key(&cryptsetup, root_partition, &key)?;
+            info!("Key removed");
+        }
+    }
+
+    Ok(())
+}
@@ -1,11 +1,21 @@
 mod chroot;
+mod flash;
+mod luks;
 mod mount;
 mod qemu;
+mod shrink;
+mod sign;
+mod update;
 
 use anyhow::Context;
 pub use chroot::chroot;
-pub use mount::mount;
+pub use flash::flash;
+pub use luks::luks;
+pub use mount::{fstab_mount_entries, fstab_subvolumes, mount, read_fstab, MountEntry};
 pub use qemu::qemu;
+pub use shrink::shrink;
+pub use sign::sign;
+pub use update::update;
 
 use std::path::PathBuf;
 use std::process::Command;
@@ -1,36 +1,211 @@
-use crate::storage::{Filesystem, MountStack};
+use super::Tool;
+use crate::storage::{filesystem_uuid, Filesystem, MountStack, Subvolume};
 use anyhow::Context;
-use log::{debug, info};
+use log::{debug, info, warn};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-/// Mounts root filesystem to given mount_path
-/// Mounts boot filesystem to mount_path/boot
+/// A filesystem (or one of its btrfs subvolumes) and where it is mounted in the installation
+pub struct MountEntry<'a> {
+    pub mount_point: PathBuf,
+    pub filesystem: &'a Filesystem<'a>,
+    pub subvolume: Option<String>,
+}
+
+impl<'a> MountEntry<'a> {
+    pub fn new(mount_point: PathBuf, filesystem: &'a Filesystem<'a>) -> Self {
+        Self {
+            mount_point,
+            filesystem,
+            subvolume: None,
+        }
+    }
+
+    fn options(&self) -> Option<String> {
+        let options: Vec<String> = self
+            .subvolume
+            .iter()
+            .map(|subvolume| format!("subvol={}", subvolume))
+            .chain(self.filesystem.fs_type().mount_options().map(String::from))
+            .collect();
+
+        if options.is_empty() {
+            None
+        } else {
+            Some(options.join(","))
+        }
+    }
+}
+
+/// Mounts the given filesystems under mount_path
+/// Parents are mounted before their children, so the root filesystem is always mounted first.
 /// Note we mount with noatime to reduce disk writes by not recording file access times
-pub fn mount<'a>(
-    mount_path: &Path,
-    boot_filesystem: &'a Filesystem,
-    root_filesystem: &'a Filesystem,
-) -> anyhow::Result<MountStack<'a>> {
+pub fn mount<'a>(mount_path: &Path, entries: &[MountEntry<'a>]) -> anyhow::Result<MountStack<'a>> {
     let mut mount_stack = MountStack::new();
-    debug!(
-        "Root partition: {}",
-        root_filesystem.block().path().display()
-    );
+
+    let mut ordered: Vec<&MountEntry> = entries.iter().collect();
+    ordered.sort_by_key(|entry| entry.mount_point.components().count());
 
     info!("Mounting filesystems to {}", mount_path.display());
-    mount_stack
-        .mount(root_filesystem, mount_path.into(), None)
-        .with_context(|| format!("Error mounting filesystem to {}", mount_path.display()))?;
+    for entry in ordered {
+        debug!(
+            "Partition {} at {}",
+            entry.filesystem.block().path().display(),
+            entry.mount_point.display()
+        );
 
-    let boot_point = mount_path.join("boot");
-    if !boot_point.exists() {
-        fs::create_dir(&boot_point).context("Error creating the boot directory")?;
-    }
+        let target = mount_path.join(
+            entry
+                .mount_point
+                .strip_prefix("/")
+                .unwrap_or(&entry.mount_point),
+        );
+        if !target.exists() {
+            fs::create_dir_all(&target)
+                .with_context(|| format!("Error creating the {} directory", target.display()))?;
+        }
 
-    mount_stack
-        .mount(boot_filesystem, boot_point, None)
-        .context("Error mounting the boot point")?;
+        mount_stack
+            .mount(entry.filesystem, target, entry.options().as_deref())
+            .with_context(|| format!("Error mounting {}", entry.mount_point.display()))?;
+    }
 
     Ok(mount_stack)
 }
+
+/// A line of an fstab: the source, the mount point, the filesystem type and the options
+pub type FstabEntry = (String, PathBuf, String, String);
+
+/// Reads the fstab of the installation mounted at root
+/// Without one, every filesystem is mounted where its partition type says.
+pub fn read_fstab(root: &Path) -> anyhow::Result<Vec<FstabEntry>> {
+    let path = root.join("etc/fstab");
+    if !path.exists() {
+        warn!("The installation has no fstab. Mounting partitions by their type");
+        return Ok(Vec::new());
+    }
+    let fstab = fs::read_to_string(path).context("Error reading the fstab")?;
+
+    Ok(fstab
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [source, target, fs_type, options, ..] => Some((
+                    String::from(*source),
+                    PathBuf::from(target),
+                    String::from(*fs_type),
+                    String::from(*options),
+                )),
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+/// Where the fstab mounts the filesystem with the given UUID, unless that is the root or swap
+/// Filesystems with subvolumes are left to fstab_subvolumes.
+pub fn fstab_mount_point(fstab: &[FstabEntry], uuid: &str) -> Option<PathBuf> {
+    let source = format!("UUID={}", uuid);
+    fstab
+        .iter()
+        .find(|(device, target, _, options)| {
+            *device == source
+                && target.is_absolute()
+                && target != Path::new("/")
+                && !options.split(',').any(|o| o.starts_with("subvol="))
+        })
+        .map(|(_, target, _, _)| target.clone())
+}
+
+/// Lists the btrfs subvolumes of the root filesystem which the installation's fstab mounts
+/// The root subvolume itself is left out since it is already mounted
+pub fn fstab_subvolumes(fstab: &[FstabEntry], uuid: &str) -> Vec<Subvolume> {
+    let source = format!("UUID={}", uuid);
+
+    let subvolumes = fstab
+        .iter()
+        .filter(|(device, target, fs_type, _)| {
+            *device == source && fs_type == "btrfs" && target != Path::new("/")
+        })
+        .filter_map(|(_, target, _, options)| {
+            options
+                .split(',')
+                .find_map(|option| option.strip_prefix("subvol="))
+                .map(|name| Subvolume {
+                    name: String::from(name.trim_start_matches('/')),
+                    mount_point: target.clone(),
+                })
+        })
+        .collect::<Vec<_>>();
+
+    debug!("Subvolumes in fstab: {:?}", subvolumes);
+    subvolumes
+}
+
+/// Pairs the filesystems with the mount points the fstab gives them
+/// Each filesystem comes with the mount point of its partition type, used when the fstab does
+/// not list it. Filesystems with neither are left out.
+pub fn fstab_mount_entries<'a>(
+    blkid: &Tool,
+    fstab: &[FstabEntry],
+    filesystems: &'a [(Option<&str>, Filesystem<'a>)],
+) -> Vec<MountEntry<'a>> {
+    let mut mount_entries = Vec::new();
+    for (fallback, filesystem) in filesystems {
+        let uuid = filesystem_uuid(blkid, filesystem.block()).ok();
+        match uuid
+            .and_then(|uuid| fstab_mount_point(fstab, &uuid))
+            .or_else(|| fallback.map(PathBuf::from))
+        {
+            Some(target) => mount_entries.push(MountEntry::new(target, filesystem)),
+            None => debug!(
+                "{} is not in the fstab. It will not be mounted",
+                filesystem.block().path().display()
+            ),
+        }
+    }
+    mount_entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn mount_points_from_fstab() {
+        let root = tempdir().unwrap();
+        fs::create_dir_all(root.path().join("etc")).unwrap();
+        fs::write(
+            root.path().join("etc/fstab"),
+            "# /dev/sda3
+UUID=1111	/	btrfs	rw,noatime,subvol=/@root	0 0
+UUID=1111	/home	btrfs	rw,noatime,subvol=/@home	0 0
+# /dev/sda1
+UUID=AB12-CD34	/efi	vfat	rw,noatime	0 2
+UUID=2222	/srv/data	ext4	rw,noatime	0 2
+/dev/mapper/alma-swap	none	swap	defaults	0 0
+",
+        )
+        .unwrap();
+
+        let fstab = read_fstab(root.path()).unwrap();
+        assert_eq!(
+            fstab_mount_point(&fstab, "AB12-CD34"),
+            Some(PathBuf::from("/efi"))
+        );
+        assert_eq!(
+            fstab_mount_point(&fstab, "2222"),
+            Some(PathBuf::from("/srv/data"))
+        );
+        assert_eq!(fstab_mount_point(&fstab, "1111"), None);
+        assert_eq!(fstab_mount_point(&fstab, "3333"), None);
+
+        let subvolumes = fstab_subvolumes(&fstab, "1111");
+        assert_eq!(subvolumes.len(), 1);
+        assert_eq!(subvolumes[0].name, "@home");
+        assert_eq!(subvolumes[0].mount_point, PathBuf::from("/home"));
+    }
+}
@@ -1,18 +1,21 @@
 use super::Tool;
 use crate::args;
+use crate::export::ImageFormat;
 use anyhow::Context;
 use log::debug;
 
 use std::os::unix::process::CommandExt as UnixCommandExt;
 use std::path::PathBuf;
 
-/// Loads given block device in qemu
+/// Loads given block device or disk image in qemu
 /// Uses kvm if it is enabled
 pub fn qemu(command: args::QemuCommand) -> anyhow::Result<()> {
     let qemu = Tool::find("qemu-system-x86_64")?;
+    let format = ImageFormat::detect(&command.block_device)?;
+    debug!("Image format: {}", format.name());
 
     let mut run = qemu.execute();
-    run.args(&[
+    run.args([
         "-m",
         "4G",
         "-netdev",
@@ -26,14 +29,15 @@ pub fn qemu(command: args::QemuCommand) -> anyhow::Result<()> {
         "-drive",
     ])
     .arg(format!(
-        "file={},if=virtio,format=raw",
-        command.block_device.display()
+        "file={},if=virtio,format={}",
+        command.block_device.display(),
+        format.name()
     ))
     .args(command.args);
 
     if PathBuf::from("/dev/kvm").exists() {
         debug!("KVM is enabled");
-        run.args(&["-enable-kvm", "-cpu", "host"]);
+        run.args(["-enable-kvm", "-cpu", "host"]);
     }
 
     let err = run.exec();
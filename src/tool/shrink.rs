@@ -0,0 +1,213 @@
+use super::Tool;
+use crate::args;
+use crate::process::CommandExt;
+use crate::storage::{
+    discover_partitions, is_encrypted_device, is_lvm_device, root_partition_index, BlockDevice,
+    EncryptedDevice, Filesystem, FilesystemType, KeySource, LoopDevice, MountStack, StorageDevice,
+};
+use anyhow::{anyhow, Context};
+use byte_unit::Byte;
+use log::{info, warn};
+use std::fs;
+use std::io::{Seek, SeekFrom};
+use std::path::Path;
+use tempfile::tempdir;
+
+/// Partitions and images are rounded up to this size
+const ALIGNMENT: u64 = 1024 * 1024;
+
+fn device_size(path: &Path) -> anyhow::Result<u64> {
+    fs::File::open(path)
+        .and_then(|mut device| device.seek(SeekFrom::End(0)))
+        .with_context(|| format!("Error querying the size of {}", path.display()))
+}
+
+fn display_size(bytes: u64) -> String {
+    Byte::from_bytes(u128::from(bytes))
+        .get_appropriate_unit(true)
+        .to_string()
+}
+
+/// The size of an ext4 filesystem, from its superblock
+fn ext4_size(dumpe2fs: &Tool, device: &dyn BlockDevice) -> anyhow::Result<u64> {
+    let output = dumpe2fs
+        .execute()
+        .arg("-h")
+        .arg(device.path())
+        .run_text_output()
+        .context("Error reading the filesystem superblock")?;
+    let field = |name: &str| -> anyhow::Result<u64> {
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|value| value.trim().parse().ok())
+            .ok_or_else(|| anyhow!("The filesystem superblock has no {}", name))
+    };
+
+    Ok(field("Block count:")? * field("Block size:")?)
+}
+
+/// Discards the unused blocks of the filesystem, which punches holes into the image file
+fn trim(fstrim: &Tool, filesystem: &Filesystem) -> anyhow::Result<()> {
+    info!("Trimming the free space of the root filesystem");
+    let mount_point = tempdir().context("Error creating a temporary directory")?;
+    let mut mount_stack = MountStack::new();
+    mount_stack
+        .mount(filesystem, mount_point.path().to_path_buf(), None)
+        .context("Error mounting the root filesystem")?;
+    fstrim
+        .execute()
+        .arg("-v")
+        .arg(mount_point.path())
+        .run()
+        .context("Error trimming the root filesystem")?;
+    mount_stack.umount()
+}
+
+/// Shrinks the root filesystem of an image to its used size, followed by the LUKS container, the
+/// root partition and the image file itself
+/// Only ext4 root filesystems in the last partition of the image can be shrunk.
+pub fn shrink(command: args::ShrinkCommand) -> anyhow::Result<()> {
+    if !fs::metadata(&command.image)
+        .with_context(|| format!("Error reading {}", command.image.display()))?
+        .is_file()
+    {
+        return Err(anyhow!("Only image files can be shrunk"));
+    }
+    if let KeySource::Generate(_) = command.luks_key {
+        return Err(anyhow!(
+            "A key cannot be generated for an existing encrypted device"
+        ));
+    }
+
+    let e2fsck = Tool::find("e2fsck")?;
+    let resize2fs = Tool::find("resize2fs")?;
+    let dumpe2fs = Tool::find("dumpe2fs")?;
+    let fstrim = if command.trim {
+        Some(Tool::find("fstrim")?)
+    } else {
+        None
+    };
+    let cryptsetup;
+
+    let image_size = device_size(&command.image)?;
+    let loop_device = LoopDevice::create(&command.image)?;
+    let storage_device = StorageDevice::from_path(loop_device.path(), false)?;
+    let sector_size = storage_device.sector_size()?;
+
+    let mut table = storage_device.read_partition_table()?;
+    let (root_number, first_lba, last_lba) = table
+        .last_partition()
+        .ok_or_else(|| anyhow!("The image has no partitions"))?;
+    let partitions = discover_partitions(&storage_device)?;
+    let root_partition_base = &partitions[root_partition_index(&partitions)?].1;
+    if root_partition_base.path() != storage_device.get_partition(root_number)?.path() {
+        return Err(anyhow!(
+            "The root partition must be the last partition of the image to shrink it"
+        ));
+    }
+
+    let key;
+    let encrypted_root = if is_encrypted_device(root_partition_base)? {
+        cryptsetup = Some(Tool::find("cryptsetup")?);
+        key = Some(command.luks_key.resolve(None)?);
+        Some(EncryptedDevice::open(
+            cryptsetup.as_ref().expect("cryptsetup not found"),
+            root_partition_base,
+            "alma_root".into(),
+            key.as_ref().expect("No key for the encrypted device"),
+        )?)
+    } else {
+        key = None;
+        None
+    };
+
+    let root_block = match &encrypted_root {
+        Some(e) => e as &dyn BlockDevice,
+        None => root_partition_base as &dyn BlockDevice,
+    };
+    if is_lvm_device(root_block)? {
+        return Err(anyhow!("Images with LVM volume groups cannot be shrunk"));
+    }
+    if FilesystemType::detect(root_block)? != Some(FilesystemType::Ext4) {
+        return Err(anyhow!("Only ext4 root filesystems can be shrunk"));
+    }
+
+    if let Some(fstrim) = &fstrim {
+        if encrypted_root.is_some() {
+            // Encrypted free space looks random and does not compress anyway
+            warn!("The free space of an encrypted root filesystem cannot be trimmed. Skipping");
+        } else {
+            trim(
+                fstrim,
+                &Filesystem::from_partition(root_block, FilesystemType::Ext4),
+            )?;
+        }
+    }
+
+    info!("Shrinking the root filesystem");
+    // resize2fs refuses to shrink a filesystem which was not checked right before
+    let status = e2fsck
+        .execute()
+        .args(["-f", "-p"])
+        .arg(root_block.path())
+        .status()
+        .context("Error checking the root filesystem")?;
+    // 1 means that errors were found and fixed
+    if !matches!(status.code(), Some(0) | Some(1)) {
+        return Err(anyhow!("Error checking the root filesystem: {}", status));
+    }
+    resize2fs
+        .execute()
+        .arg("-M")
+        .arg(root_block.path())
+        .run()
+        .context("Error shrinking the root filesystem")?;
+    let filesystem_size = ext4_size(&dumpe2fs, root_block)?;
+
+    // The LUKS header sits in front of the filesystem
+    let partition_size = (last_lba - first_lba + 1) * sector_size;
+    let mut root_size = filesystem_size;
+    if let Some(encrypted_root) = &encrypted_root {
+        info!("Shrinking the encrypted container");
+        root_size += partition_size - device_size(encrypted_root.path())?;
+        encrypted_root.resize(
+            key.as_ref().expect("No key for the encrypted device"),
+            filesystem_size / 512,
+        )?;
+    }
+
+    drop(encrypted_root);
+    drop(partitions);
+    drop(storage_device);
+    drop(loop_device);
+
+    let root_sectors = root_size.div_ceil(ALIGNMENT) * ALIGNMENT / sector_size;
+    if first_lba + root_sectors > last_lba {
+        info!("The root partition cannot be shrunk any further");
+        return Ok(());
+    }
+
+    info!("Shrinking the root partition and the image");
+    table.set_partition_end(root_number, first_lba + root_sectors - 1)?;
+    let new_image_size = table.required_disk_size().div_ceil(ALIGNMENT) * ALIGNMENT;
+    let mut image = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&command.image)
+        .context("Error opening the image")?;
+    image
+        .set_len(new_image_size)
+        .context("Error truncating the image")?;
+    // Also moves the backup GPT to the new end of the image. The boot code of GRUB in front of
+    // the first partition stays, so the image still boots on BIOS
+    table.update(&mut image)?;
+
+    info!(
+        "Shrank {} from {} to {}",
+        command.image.display(),
+        display_size(image_size),
+        display_size(new_image_size)
+    );
+    Ok(())
+}
@@ -0,0 +1,34 @@
+use super::Tool;
+use crate::args;
+use crate::secure_boot::{self, SigningKey};
+use crate::storage;
+use crate::storage::{discover_partitions, Filesystem, FilesystemType, MountStack, PartitionType};
+use anyhow::{anyhow, Context};
+use tempfile::tempdir;
+
+/// Signs the bootloader and the unified kernel images on the EFI system partition of an existing
+/// device or image, e.g. after a kernel update rebuilt the image
+pub fn sign(command: args::SignCommand) -> anyhow::Result<()> {
+    let sbsign = Tool::find("sbsign")?;
+    let sbverify = Tool::find("sbverify")?;
+    let signing_key = SigningKey::new(command.secure_boot_key, command.secure_boot_cert)?;
+
+    let (_loop_device, storage_device) =
+        storage::open_device_or_image(&command.block_device, command.allow_non_removable)?;
+
+    let partitions = discover_partitions(&storage_device)?;
+    let esp_partition = &partitions
+        .iter()
+        .find(|(partition_type, _)| *partition_type == PartitionType::Esp)
+        .ok_or_else(|| anyhow!("Cannot find the EFI system partition"))?
+        .1;
+    let esp = Filesystem::from_partition(esp_partition, FilesystemType::Vfat);
+
+    let mount_point = tempdir().context("Error creating a temporary directory")?;
+    let mut mount_stack = MountStack::new();
+    mount_stack
+        .mount(&esp, mount_point.path().to_path_buf(), None)
+        .context("Error mounting the EFI system partition")?;
+    secure_boot::sign_esp(&sbsign, &sbverify, &signing_key, mount_point.path())?;
+    mount_stack.umount()
+}
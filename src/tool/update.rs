@@ -0,0 +1,176 @@
+use super::Tool;
+use super::{fstab_mount_entries, mount, read_fstab};
+use crate::ab::{self, GrubEnv};
+use crate::args;
+use crate::process::CommandExt;
+use crate::storage;
+use crate::storage::{
+    discover_partitions, filesystem_uuid, is_encrypted_device, BlockDevice, Filesystem,
+    FilesystemType, MountStack, PartitionType,
+};
+use anyhow::{anyhow, Context};
+use log::{info, warn};
+use std::fs;
+use tempfile::tempdir;
+
+/// Copies the active slot of a device with A/B slots into the inactive one, updates the copy and
+/// makes GRUB boot it next
+/// The inactive slot is marked as not bootable until the update is done, so an update which fails
+/// halfway leaves the device booting the active slot.
+pub fn update(command: args::UpdateCommand) -> anyhow::Result<()> {
+    let arch_chroot = Tool::find("arch-chroot")?;
+    let blkid = Tool::find("blkid")?;
+    let cp = Tool::find("cp")?;
+
+    let (_loop_device, storage_device) =
+        storage::open_device_or_image(&command.block_device, command.allow_non_removable)?;
+
+    let partitions = discover_partitions(&storage_device)?;
+    let slots = ab::slot_partitions(&partitions).ok_or_else(|| {
+        anyhow!(
+            "{} has no A/B slots. Create it with --ab",
+            command.block_device.display()
+        )
+    })?;
+    let esp_partition = &partitions
+        .iter()
+        .find(|(partition_type, _)| *partition_type == PartitionType::Esp)
+        .ok_or_else(|| anyhow!("Cannot find the EFI system partition"))?
+        .1;
+    let esp = Filesystem::from_partition(esp_partition, FilesystemType::Vfat);
+
+    let esp_mount_point = tempdir().context("Error creating a temporary directory")?;
+    let mut esp_mount_stack = MountStack::new();
+    esp_mount_stack
+        .mount(&esp, esp_mount_point.path().to_path_buf(), None)
+        .context("Error mounting the EFI system partition")?;
+    let env_path = esp_mount_point.path().join(ab::GRUBENV_PATH);
+    let mut env = GrubEnv::read(&env_path)?;
+
+    let active = env.active_slot();
+    let target = active.other();
+    info!(
+        "Slot {} is active. Updating slot {}",
+        active.name(),
+        target.name()
+    );
+
+    // Anything which stops the update before the copy has to fail before the slot state changes
+    let source_partition = &partitions[slots[active.index()]].1;
+    let target_partition = &partitions[slots[target.index()]].1;
+    if is_encrypted_device(source_partition)? {
+        return Err(anyhow!("Encrypted A/B slots are not supported"));
+    }
+    let fs_type = FilesystemType::detect(source_partition)?
+        .ok_or_else(|| anyhow!("Cannot detect the filesystem of slot {}", active.name()))?;
+    let mkfs = Tool::find(fs_type.mkfs())?;
+
+    // The partitions shared by both slots, mounted in the copy where its fstab says
+    let mut filesystems = Vec::new();
+    for (i, (partition_type, partition)) in partitions.iter().enumerate() {
+        if slots.contains(&i) || *partition_type == PartitionType::Esp {
+            continue;
+        }
+
+        let fallback = partition_type
+            .discoverable_mount_point()
+            .filter(|mount_point| *mount_point != "/");
+
+        match FilesystemType::detect(partition)? {
+            Some(fs_type) => {
+                filesystems.push((fallback, Filesystem::from_partition(partition, fs_type)))
+            }
+            None if fallback.is_some() => warn!(
+                "Cannot detect the filesystem of {}. It will not be mounted",
+                partition.path().display()
+            ),
+            None => (),
+        }
+    }
+
+    env.set_slot(target, false, false);
+    env.write(&env_path)?;
+
+    info!("Copying slot {} to slot {}", active.name(), target.name());
+    let source = Filesystem::from_partition(source_partition, fs_type);
+    let target_filesystem =
+        Filesystem::format(target_partition, fs_type, Some(target.label()), &mkfs)?;
+
+    let source_mount_point = tempdir().context("Error creating a temporary directory")?;
+    let target_mount_point = tempdir().context("Error creating a temporary directory")?;
+    let mut mount_stack = MountStack::new();
+    mount_stack
+        .mount(&source, source_mount_point.path().to_path_buf(), None)
+        .with_context(|| format!("Error mounting slot {}", active.name()))?;
+    mount_stack
+        .mount(
+            &target_filesystem,
+            target_mount_point.path().to_path_buf(),
+            fs_type.mount_options(),
+        )
+        .with_context(|| format!("Error mounting slot {}", target.name()))?;
+    cp.execute()
+        .arg("-a")
+        .arg(source_mount_point.path().join("."))
+        .arg(target_mount_point.path())
+        .run()
+        .context("Error copying the system")?;
+
+    // The copy lives in a new filesystem, which the fstab has to mount as the root instead
+    let fstab_path = target_mount_point.path().join("etc/fstab");
+    let fstab = fs::read_to_string(&fstab_path)
+        .context("Error reading the fstab")?
+        .replace(
+            &format!("UUID={}", filesystem_uuid(&blkid, source_partition)?),
+            &format!("UUID={}", filesystem_uuid(&blkid, target_partition)?),
+        );
+    fs::write(&fstab_path, fstab).context("Error writing the fstab")?;
+    fs::write(target_mount_point.path().join(ab::SLOT_FILE), target.name())
+        .context("Error writing the slot name")?;
+
+    let target_esp = target_mount_point
+        .path()
+        .join(ab::ESP_MOUNT_POINT.trim_start_matches('/'));
+    fs::create_dir_all(&target_esp).context("Error creating the EFI directory")?;
+    mount_stack
+        .bind_mount(esp_mount_point.path().to_path_buf(), target_esp, None)
+        .context("Error mounting the EFI system partition")?;
+    let fstab = read_fstab(target_mount_point.path())?;
+    let shared_mount_stack = mount(
+        target_mount_point.path(),
+        &fstab_mount_entries(&blkid, &fstab, &filesystems),
+    )?;
+
+    info!("Updating slot {}", target.name());
+    let mut update = arch_chroot.execute();
+    update.arg(target_mount_point.path());
+    if command.command.is_empty() {
+        update.args(["pacman", "-Syu", "--noconfirm"]);
+    } else {
+        update.args(&command.command);
+    }
+    update.run().context("Error updating the system")?;
+
+    // The GRUB configuration of the slot searches the root filesystem by its UUID
+    arch_chroot
+        .execute()
+        .arg(target_mount_point.path())
+        .args(["grub-mkconfig", "-o", "/boot/grub/grub.cfg"])
+        .run()
+        .context("Failed to generate the GRUB configuration")?;
+
+    info!("Unmounting filesystems");
+    shared_mount_stack.umount()?;
+    mount_stack.umount()?;
+
+    env.switch_to(target);
+    env.write(&env_path)?;
+    esp_mount_stack.umount()?;
+
+    info!(
+        "Slot {} boots next. If it does not come up, the next boot falls back to slot {}",
+        target.name(),
+        active.name()
+    );
+    Ok(())
+}